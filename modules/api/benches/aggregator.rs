@@ -0,0 +1,64 @@
+//! Assembling the aggregator of a manager start with a few thousand endpoints, either
+//! once and shared by all servers, or cloned and extended for each server.
+
+use actix_web::web;
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::future;
+
+use std::sync::Arc;
+
+use api::{ApiAccess, ApiAggregator, ApiBuilder};
+
+const SERVICES: usize = 30;
+const ENDPOINTS_PER_SERVICE: usize = 100;
+const ACCESSES: [ApiAccess; 3] = [ApiAccess::Public, ApiAccess::Private, ApiAccess::Internal];
+
+fn handler(height: u64) -> future::Ready<api::Result<u64>> {
+    future::ok(height)
+}
+
+fn services(prefix: &str) -> Vec<(String, ApiBuilder)> {
+    (0..SERVICES)
+        .map(|service| {
+            let mut builder = ApiBuilder::new();
+            for endpoint in 0..ENDPOINTS_PER_SERVICE {
+                let name = format!("v1/endpoint-{}", endpoint);
+                builder.public_scope().endpoint(&name, handler);
+                builder.private_scope().endpoint_mut(&name, handler);
+            }
+            (format!("{}-{}", prefix, service), builder)
+        })
+        .collect()
+}
+
+fn aggregator(c: &mut Criterion) {
+    let mut config_aggregator = ApiAggregator::new();
+    config_aggregator.extend(services("config"));
+    let endpoints = services("update");
+
+    let mut group = c.benchmark_group("aggregator");
+    group.bench_function("shared", |b| {
+        b.iter(|| {
+            let mut aggregator = config_aggregator.clone();
+            aggregator.extend(endpoints.iter().cloned());
+            let aggregator = Arc::new(aggregator);
+            for access in ACCESSES {
+                let aggregator = Arc::clone(&aggregator);
+                aggregator.extend_backend(access, web::scope("api"));
+            }
+        })
+    });
+    group.bench_function("cloned_per_server", |b| {
+        b.iter(|| {
+            for access in ACCESSES {
+                let mut aggregator = config_aggregator.clone();
+                aggregator.extend(endpoints.clone());
+                aggregator.extend_backend(access, web::scope("api"));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, aggregator);
+criterion_main!(benches);
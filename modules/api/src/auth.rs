@@ -0,0 +1,133 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    error::{header, HeaderMap, HeaderName},
+    Error, HttpStatusCode,
+};
+
+/// Header checked for API keys besides `Authorization` when no headers are specified.
+const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+#[derive(Debug)]
+struct ApiKeyState {
+    header_names: Vec<HeaderName>,
+    keys: RwLock<HashSet<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKeyAuth {
+    inner: Arc<ApiKeyState>,
+}
+
+impl ApiKeyAuth {
+    /// Creates an authenticator accepting `keys` in any of `header_names`. An empty
+    /// `header_names` falls back to `X-Api-Key` and `Authorization`.
+    pub fn new<I>(mut header_names: Vec<HeaderName>, keys: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        if header_names.is_empty() {
+            header_names = vec![X_API_KEY, header::AUTHORIZATION];
+        }
+        Self {
+            inner: Arc::new(ApiKeyState {
+                header_names,
+                keys: RwLock::new(keys.into_iter().collect()),
+            }),
+        }
+    }
+
+    pub fn replace_keys<I>(&self, keys: I)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let keys = keys.into_iter().collect();
+        *self.inner.keys.write().unwrap() = keys;
+    }
+
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let keys = self.inner.keys.read().unwrap();
+        self.inner
+            .header_names
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(name)?.to_str().ok()?;
+                if *name == header::AUTHORIZATION {
+                    Some(strip_bearer(value))
+                } else {
+                    Some(value)
+                }
+            })
+            .any(|key| {
+                // Every key is compared in full so that timing reveals no key prefixes.
+                let key = key.trim().as_bytes();
+                keys.iter().fold(false, |found, known| {
+                    found | constant_time_eq(known.as_bytes(), key)
+                })
+            })
+    }
+
+    pub(crate) fn error(&self) -> Error {
+        Error::new(HttpStatusCode::UNAUTHORIZED)
+            .title("Unauthorized")
+            .detail("Missing or invalid API key")
+    }
+}
+
+/// Strips the authentication scheme, which is case-insensitive, from a bearer token.
+fn strip_bearer(value: &str) -> &str {
+    match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("bearer ") => &value[7..],
+        _ => value,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> ApiKeyAuth {
+        ApiKeyAuth::new(vec![header::AUTHORIZATION], vec!["secret".to_owned()])
+    }
+
+    fn headers(authorization: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            header::HeaderValue::from_static(authorization),
+        );
+        headers
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        assert!(auth().is_authorized(&headers("Bearer secret")));
+        assert!(auth().is_authorized(&headers("bearer secret")));
+        assert!(auth().is_authorized(&headers("BEARER secret")));
+        assert!(auth().is_authorized(&headers("secret")));
+    }
+
+    #[test]
+    fn wrong_keys_are_rejected() {
+        assert!(!auth().is_authorized(&headers("Bearer secreT")));
+        assert!(!auth().is_authorized(&headers("Bearer secret2")));
+        assert!(!auth().is_authorized(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn empty_header_names_fall_back_to_defaults() {
+        let auth = ApiKeyAuth::new(Vec::new(), vec!["secret".to_owned()]);
+        assert!(auth.is_authorized(&headers("Bearer secret")));
+
+        let mut api_key = HeaderMap::new();
+        api_key.insert(X_API_KEY, header::HeaderValue::from_static("secret"));
+        assert!(auth.is_authorized(&api_key));
+    }
+}
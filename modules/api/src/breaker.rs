@@ -0,0 +1,185 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{error::header, Error, HttpStatusCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    window_start: Option<Instant>,
+    opened_at: Option<Instant>,
+    probe: Option<(u64, Instant)>,
+    next_probe_id: u64,
+}
+
+/// Issued by `CircuitBreaker::try_acquire` for a request allowed through; `probe` is set
+/// for the single request let through while the breaker is half-open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BreakerPermit {
+    probe: Option<u64>,
+}
+
+#[derive(Debug)]
+struct BreakerConfig {
+    failure_threshold: u32,
+    window: Duration,
+    cool_down: Duration,
+}
+
+/// Short-circuits an endpoint with `503 Service Unavailable` after `failure_threshold`
+/// server errors within `window`, for `cool_down`; then lets a single probe request
+/// through to decide whether to close again.
+///
+/// The state is shared by clones, so reusing the same breaker when endpoints are rebuilt
+/// keeps it across reloads.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    config: Arc<BreakerConfig>,
+    state: Arc<Mutex<BreakerState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, window: Duration, cool_down: Duration) -> Self {
+        assert!(failure_threshold > 0, "Failure threshold must be positive");
+        Self {
+            config: Arc::new(BreakerConfig {
+                failure_threshold,
+                window,
+                cool_down,
+            }),
+            state: Arc::default(),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.config.cool_down => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    pub(crate) fn try_acquire(&self) -> Option<BreakerPermit> {
+        let mut state = self.state.lock().unwrap();
+        let opened_at = match state.opened_at {
+            None => return Some(BreakerPermit { probe: None }),
+            Some(opened_at) => opened_at,
+        };
+        if opened_at.elapsed() < self.config.cool_down {
+            return None;
+        }
+
+        // Half-open: let one probe through; a probe that never reports back expires.
+        match state.probe {
+            Some((_, started)) if started.elapsed() < self.config.cool_down => None,
+            _ => {
+                let id = state.next_probe_id;
+                state.next_probe_id += 1;
+                state.probe = Some((id, Instant::now()));
+                Some(BreakerPermit { probe: Some(id) })
+            }
+        }
+    }
+
+    pub(crate) fn record(&self, permit: BreakerPermit, is_failure: bool) {
+        let mut state = self.state.lock().unwrap();
+        // Once the breaker is open, only the current probe decides its state; results of
+        // requests admitted earlier or of expired probes are stale.
+        if state.opened_at.is_some() {
+            match (state.probe, permit.probe) {
+                (Some((current, _)), Some(probe)) if current == probe => {}
+                _ => return,
+            }
+        }
+
+        let now = Instant::now();
+        if !is_failure {
+            *state = BreakerState {
+                next_probe_id: state.next_probe_id,
+                ..BreakerState::default()
+            };
+            return;
+        }
+        if state.probe.take().is_some() {
+            state.opened_at = Some(now);
+            return;
+        }
+        match state.window_start {
+            Some(start) if now.duration_since(start) <= self.config.window => state.failures += 1,
+            _ => {
+                state.window_start = Some(now);
+                state.failures = 1;
+            }
+        }
+        if state.failures >= self.config.failure_threshold {
+            state.opened_at = Some(now);
+        }
+    }
+
+    pub(crate) fn error(&self) -> Error {
+        Error::new(HttpStatusCode::SERVICE_UNAVAILABLE)
+            .title("Service temporarily unavailable")
+            .detail("The endpoint is failing and has been temporarily disabled")
+            .header(
+                header::RETRY_AFTER,
+                &self.config.cool_down.as_secs().max(1).to_string(),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_millis(10));
+        let permit = breaker.try_acquire().unwrap();
+        breaker.record(permit, true);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        breaker
+    }
+
+    #[test]
+    fn late_results_are_ignored_while_open() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_millis(10));
+        let late = breaker.try_acquire().unwrap();
+        for _ in 0..2 {
+            let permit = breaker.try_acquire().unwrap();
+            breaker.record(permit, true);
+        }
+        breaker.record(late, false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn only_the_probe_closes_the_breaker() {
+        let breaker = open_breaker();
+        std::thread::sleep(Duration::from_millis(20));
+        let probe = breaker.try_acquire().unwrap();
+        assert!(breaker.try_acquire().is_none());
+
+        breaker.record(BreakerPermit { probe: None }, false);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record(probe, false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn failed_probe_reopens_the_breaker() {
+        let breaker = open_breaker();
+        std::thread::sleep(Duration::from_millis(20));
+        let probe = breaker.try_acquire().unwrap();
+        breaker.record(probe, true);
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+}
@@ -0,0 +1,342 @@
+use actix_web::HttpRequest;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{header, HeaderMap, HttpStatusCode},
+    Error as ApiError,
+};
+
+/// Deadline of the request as a unix timestamp in milliseconds.
+pub const DEADLINE: &str = "x-deadline";
+/// Time budget of the request in the gRPC format, such as `500m` for 500 milliseconds.
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+tokio::task_local! {
+    static REQUEST_CONTEXT: RequestContext;
+}
+
+/// Header in which trusted proxies record the addresses of the clients they forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrustedProxies {
+    pub(crate) networks: Vec<IpNetwork>,
+    pub(crate) header: ForwardedHeader,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PublicBaseUrl(pub(crate) Option<String>);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DefaultTimeout(pub(crate) Option<Duration>);
+
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    peer_addr: Option<SocketAddr>,
+    client_ip: Option<IpAddr>,
+    forwarded_base: Option<String>,
+    deadline: Option<Instant>,
+    #[cfg(feature = "tls")]
+    client_identity: Option<crate::ClientIdentity>,
+}
+
+impl RequestContext {
+    pub fn current() -> Option<Self> {
+        REQUEST_CONTEXT.try_with(Clone::clone).ok()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_ip
+    }
+
+    /// Instant after which the request fails with `504 Gateway Timeout`.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left until the request deadline.
+    pub fn remaining_budget(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Identity from the client certificate verified during the TLS handshake.
+    #[cfg(feature = "tls")]
+    pub fn client_identity(&self) -> Option<&crate::ClientIdentity> {
+        self.client_identity.as_ref()
+    }
+
+    /// Scheme and host the client used to reach the server: the configured public base URL
+    /// if any, else the one reported by a trusted proxy, else the `Host` header.
+    pub fn forwarded_base(&self) -> Option<String> {
+        self.forwarded_base.clone()
+    }
+
+    pub(crate) fn from_request(request: &HttpRequest) -> Self {
+        let peer_addr = request.peer_addr();
+        let proxies = request.app_data::<TrustedProxies>();
+        let client_ip = peer_addr.map(|addr| match proxies {
+            Some(proxies) => resolve_client_ip(
+                addr.ip(),
+                request.headers(),
+                &proxies.networks,
+                proxies.header,
+            ),
+            None => addr.ip(),
+        });
+        let from_proxy = peer_addr.zip(proxies).and_then(|(addr, proxies)| {
+            proxies
+                .networks
+                .iter()
+                .any(|network| network.contains(addr.ip()))
+                .then_some(proxies.header)
+        });
+        let forwarded_base = match request.app_data::<PublicBaseUrl>() {
+            Some(PublicBaseUrl(Some(base))) => Some(base.trim_end_matches('/').to_owned()),
+            _ => forwarded_base(request.headers(), from_proxy),
+        };
+        let default_timeout = request
+            .app_data::<DefaultTimeout>()
+            .and_then(|timeout| timeout.0);
+
+        Self {
+            peer_addr,
+            client_ip,
+            forwarded_base,
+            deadline: request_deadline(request.headers(), default_timeout),
+            #[cfg(feature = "tls")]
+            client_identity: request.conn_data::<crate::ClientIdentity>().cloned(),
+        }
+    }
+
+    pub(crate) async fn scope<F, T>(self, f: F) -> Result<T, ApiError>
+    where
+        F: std::future::Future<Output = Result<T, ApiError>>,
+    {
+        match self.deadline {
+            Some(deadline) => {
+                let f = tokio::time::timeout_at(deadline.into(), f);
+                match REQUEST_CONTEXT.scope(self, f).await {
+                    Ok(response) => response,
+                    Err(_) => Err(ApiError::new(HttpStatusCode::GATEWAY_TIMEOUT)
+                        .title("Deadline exceeded")
+                        .detail("Request was not handled before its deadline")),
+                }
+            }
+            None => REQUEST_CONTEXT.scope(self, f).await,
+        }
+    }
+}
+
+/// Resolves the request deadline from the `X-Deadline` or `grpc-timeout` headers, falling
+/// back to `default_timeout` if neither is present and valid. A client deadline never
+/// extends the default timeout.
+fn request_deadline(headers: &HeaderMap, default_timeout: Option<Duration>) -> Option<Instant> {
+    let header_value = |name: &str| headers.get(name)?.to_str().ok();
+    let client_budget = header_value(DEADLINE)
+        .and_then(parse_deadline)
+        .or_else(|| header_value(GRPC_TIMEOUT).and_then(parse_grpc_timeout));
+
+    let budget = match (client_budget, default_timeout) {
+        (Some(client), Some(default)) => client.min(default),
+        (budget, default) => budget.or(default)?,
+    };
+    Instant::now().checked_add(budget)
+}
+
+fn forwarded_base(headers: &HeaderMap, from_proxy: Option<ForwardedHeader>) -> Option<String> {
+    let forwarded_param = |name: &str| {
+        if from_proxy != Some(ForwardedHeader::Forwarded) {
+            return None;
+        }
+        headers
+            .get(header::FORWARDED)?
+            .to_str()
+            .ok()?
+            .split(',')
+            .next()?
+            .split(';')
+            .find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case(name)
+                    .then(|| value.trim_matches('"').to_owned())
+            })
+    };
+    let header_value = |name: &str| {
+        if name != header::HOST.as_str() && from_proxy != Some(ForwardedHeader::XForwardedFor) {
+            return None;
+        }
+        let value = headers.get(name)?.to_str().ok()?;
+        Some(value.split(',').next()?.trim().to_owned())
+    };
+
+    let host = forwarded_param("host")
+        .or_else(|| header_value("x-forwarded-host"))
+        .or_else(|| header_value(header::HOST.as_str()))?;
+    let proto = forwarded_param("proto")
+        .or_else(|| header_value("x-forwarded-proto"))
+        .unwrap_or_else(|| "http".to_owned());
+    Some(format!("{}://{}", proto, host))
+}
+
+fn parse_deadline(value: &str) -> Option<Duration> {
+    let deadline = UNIX_EPOCH.checked_add(Duration::from_millis(value.trim().parse().ok()?))?;
+    Some(
+        deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Resolves the client address from the `header` hops appended by `trusted` proxies; hops
+/// recorded in the other forwarding header are ignored, as clients may set it themselves.
+/// The walk stops at a hop that is not an IP address, such as `unknown` or an obfuscated
+/// identifier, and falls back to the last trusted hop.
+pub fn resolve_client_ip(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    trusted: &[IpNetwork],
+    header: ForwardedHeader,
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(*ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let mut hops = forwarded_hops(headers, header);
+    let mut client = peer;
+    while let Some(Some(hop)) = hops.pop() {
+        client = hop;
+        if !is_trusted(&hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// Forwarding hops in order; `None` stands for a hop without a parseable IP address.
+fn forwarded_hops(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    if header == ForwardedHeader::XForwardedFor {
+        return headers
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|node| parse_node(node.trim()))
+            .collect();
+    }
+
+    headers
+        .get_all(header::FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if key.eq_ignore_ascii_case("for") {
+                    parse_node(value.trim_matches('"'))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|rest| rest.split(']').next())
+        .and_then(|ip| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_ip(header: ForwardedHeader, name: &str, value: &str) -> IpAddr {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            name.parse::<header::HeaderName>().unwrap(),
+            header::HeaderValue::from_str(value).unwrap(),
+        );
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        resolve_client_ip("10.0.0.1".parse().unwrap(), &headers, &trusted, header)
+    }
+
+    #[test]
+    fn client_ip_is_the_first_untrusted_hop() {
+        let ip = client_ip(
+            ForwardedHeader::XForwardedFor,
+            "x-forwarded-for",
+            "1.2.3.4, 5.6.7.8, 10.0.0.2",
+        );
+        assert_eq!(ip, "5.6.7.8".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn unparseable_hops_stop_the_walk() {
+        let ip = client_ip(
+            ForwardedHeader::XForwardedFor,
+            "x-forwarded-for",
+            "1.2.3.4, garbage",
+        );
+        assert_eq!(ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+
+        let ip = client_ip(
+            ForwardedHeader::XForwardedFor,
+            "x-forwarded-for",
+            "1.2.3.4, unknown, 10.0.0.2",
+        );
+        assert_eq!(ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+
+        let ip = client_ip(
+            ForwardedHeader::Forwarded,
+            "forwarded",
+            "for=1.2.3.4, for=_hidden",
+        );
+        assert_eq!(ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+    }
+}
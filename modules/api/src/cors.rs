@@ -1,6 +1,7 @@
-use anyhow::bail;
+use anyhow::Context;
 use serde::{de, ser};
-use std::{fmt, str::FromStr};
+use std::{env, fmt, str::FromStr};
+use thiserror::Error;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[non_exhaustive]
@@ -9,6 +10,30 @@ pub enum AllowOrigin {
     Whitelist(Vec<String>),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum ParseAllowOriginError {
+    #[error("Invalid AllowOrigin::Whitelist value: no hosts specified")]
+    EmptyInput,
+    #[error("Invalid AllowOrigin::Whitelist entry `{0}`")]
+    InvalidEntry(String),
+}
+
+impl AllowOrigin {
+    pub fn from_env(var: &str) -> anyhow::Result<Option<Self>> {
+        let value = match env::var(var) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Invalid `{}` variable", var)),
+        };
+
+        value
+            .parse()
+            .map(Some)
+            .with_context(|| format!("Unable to parse `{}` variable as allowed origins", var))
+    }
+}
+
 impl ser::Serialize for AllowOrigin {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -66,7 +91,7 @@ impl<'de> de::Deserialize<'de> for AllowOrigin {
 }
 
 impl FromStr for AllowOrigin {
-    type Err = anyhow::Error;
+    type Err = ParseAllowOriginError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "*" {
@@ -79,7 +104,13 @@ impl FromStr for AllowOrigin {
             .filter(|s| !s.is_empty())
             .collect();
         if v.is_empty() {
-            bail!("Invalid AllowOrigin::Whitelist value");
+            return Err(ParseAllowOriginError::EmptyInput);
+        }
+        if let Some(entry) = v
+            .iter()
+            .find(|host| *host == "*" || host.contains(char::is_whitespace))
+        {
+            return Err(ParseAllowOriginError::InvalidEntry(entry.clone()));
         }
 
         Ok(AllowOrigin::Whitelist(v))
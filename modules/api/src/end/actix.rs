@@ -7,26 +7,55 @@ pub use actix_web::{
     HttpRequest, HttpResponse,
 };
 
+use actix_cors::CorsMiddleware;
 use actix_web::{
-    body::{BodySize, BoxBody, MessageBody},
-    dev::ServiceResponse,
-    error::ResponseError,
+    body::{self, BodySize, BoxBody, MessageBody},
+    dev::{forward_ready, Decompress, Service, ServiceRequest, ServiceResponse, Transform},
+    error::{JsonPayloadError, PayloadError, QueryPayloadError, ResponseError},
     http::header,
     middleware::{ErrorHandlerResponse, ErrorHandlers},
     web::{self, scope, Json, Query},
     FromRequest,
 };
+use actix_web::{
+    http::header::{HeaderName, HeaderValue},
+    mime, HttpMessage,
+};
 use futures::{
-    future::{Future, LocalBoxFuture},
+    future::{self, Future, LocalBoxFuture, Ready},
     prelude::*,
+    stream::LocalBoxStream,
 };
 use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+use time::{
+    format_description::FormatItem, macros::format_description, OffsetDateTime, PrimitiveDateTime,
+    UtcOffset,
+};
 
-use std::{fmt, sync::Arc};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    panic::AssertUnwindSafe,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
+#[cfg(debug_assertions)]
+use crate::schema::SchemaCheck;
 use crate::{
-    Actuality, AllowOrigin, ApiBackend, ApiScope, EndpointMutability, Error as ApiError,
-    ExtendApiBackend, NamedWith,
+    error::ParseErrorCode,
+    idempotency::{CachedResponse, Idempotency, IdempotencyState, IDEMPOTENCY_KEY},
+    manager::{
+        BodyLogging, CompressionConfig, DeprecationHeader, QueryFormat, DEFAULT_JSON_PAYLOAD_SIZE,
+    },
+    response_meta, Actuality, AllowOrigin, ApiBackend, ApiKeyAuth, ApiScope, CacheControl,
+    EndpointMutability, Error as ApiError, ErrorBody, ErrorFieldCase, ErrorFormat,
+    ExtendApiBackend, MaintenanceMode, NamedWith, RequestContext,
 };
 
 pub type RawHandler = dyn Fn(HttpRequest, Payload) -> LocalBoxFuture<'static, Result<HttpResponse, actix_web::Error>>
@@ -34,10 +63,18 @@ pub type RawHandler = dyn Fn(HttpRequest, Payload) -> LocalBoxFuture<'static, Re
     + Send
     + Sync;
 
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+
+pub type BodyStream = LocalBoxStream<'static, Result<Bytes, ApiError>>;
+
 #[derive(Clone)]
 pub struct RequestHandler {
     pub name: String,
     pub method: actix_web::http::Method,
+    pub actuality: Actuality,
+    pub summary: String,
+    pub description: String,
     pub inner: Arc<RawHandler>,
 }
 
@@ -46,19 +83,255 @@ impl fmt::Debug for RequestHandler {
         f.debug_struct("RequestHandler")
             .field("name", &self.name)
             .field("method", &self.method)
+            .field("actuality", &self.actuality)
+            .field("summary", &self.summary)
+            .field("description", &self.description)
             .finish()
     }
 }
 
+#[derive(Clone)]
+struct FallbackHandler(Arc<RawHandler>);
+
+impl fmt::Debug for FallbackHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FallbackHandler").finish()
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ApiBuilder {
     handlers: Vec<RequestHandler>,
+    fallback: Option<FallbackHandler>,
 }
 
 impl ApiBuilder {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Lists the name, method, summary and description of every endpoint.
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &HttpMethod, &str, &str)> {
+        self.handlers.iter().map(|h| {
+            (
+                h.name.as_str(),
+                &h.method,
+                h.summary.as_str(),
+                h.description.as_str(),
+            )
+        })
+    }
+
+    pub fn handlers(&self) -> impl Iterator<Item = &RequestHandler> {
+        self.handlers.iter()
+    }
+
+    pub fn raw_endpoint<Q, R, F>(
+        &mut self,
+        name: &str,
+        mutability: EndpointMutability,
+        handler: F,
+    ) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        F: Fn(Q) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = Result<HttpResponse, ApiError>> + 'static,
+    {
+        let index = move |request: HttpRequest, payload: Payload| {
+            let handler = handler.clone();
+            let context = RequestContext::from_request(&request);
+
+            async move {
+                let query = extract_query(request, payload, mutability, false, false).await?;
+                let response = context.scope(handler(query)).await?;
+                Ok(response)
+            }
+            .boxed_local()
+        };
+
+        self.raw_handler(RequestHandler {
+            name: name.to_owned(),
+            method: mutability.into(),
+            actuality: Actuality::Actual,
+            summary: String::new(),
+            description: String::new(),
+            inner: Arc::from(index) as Arc<RawHandler>,
+        })
+    }
+
+    pub fn streaming_endpoint<Q, I, R, F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        I: Serialize + 'static,
+        F: Fn(Q, BodyStream) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = Result<I, ApiError>> + 'static,
+    {
+        let index = move |request: HttpRequest, payload: Payload| {
+            let handler = handler.clone();
+            let context = RequestContext::from_request(&request);
+
+            async move {
+                check_query_len(&request).await?;
+                let query = parse_query_string(&request).await?;
+                let body = payload
+                    .map_err(|e| {
+                        ApiError::bad_request()
+                            .title("Request body read error")
+                            .detail(e.to_string())
+                    })
+                    .boxed_local();
+                let response = context.scope(handler(query, body)).await?;
+                Ok(json_response(
+                    Actuality::Actual,
+                    &DeprecationHeaders::default(),
+                    None,
+                    None,
+                    response,
+                ))
+            }
+            .boxed_local()
+        };
+
+        self.raw_handler(RequestHandler {
+            name: name.to_owned(),
+            method: EndpointMutability::Mutable.into(),
+            actuality: Actuality::Actual,
+            summary: String::new(),
+            description: String::new(),
+            inner: Arc::from(index) as Arc<RawHandler>,
+        })
+    }
+
+    pub fn raw_body_endpoint<Q, I, R, F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        I: Serialize + 'static,
+        F: Fn(Q, Bytes) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = Result<I, ApiError>> + 'static,
+    {
+        let index = move |request: HttpRequest, payload: Payload| {
+            let handler = handler.clone();
+            let context = RequestContext::from_request(&request);
+
+            async move {
+                let (query, body) = extract_body(&request, payload).await?;
+                let response = context.scope(handler(query, body)).await?;
+                Ok(json_response(
+                    Actuality::Actual,
+                    &DeprecationHeaders::default(),
+                    None,
+                    None,
+                    response,
+                ))
+            }
+            .boxed_local()
+        };
+
+        self.raw_handler(RequestHandler {
+            name: name.to_owned(),
+            method: EndpointMutability::Mutable.into(),
+            actuality: Actuality::Actual,
+            summary: String::new(),
+            description: String::new(),
+            inner: Arc::from(index) as Arc<RawHandler>,
+        })
+    }
+
+    pub fn proto_sources(&mut self, sources: &[(&str, &str)]) -> &mut Self {
+        let sources: Arc<BTreeMap<String, String>> = Arc::new(
+            sources
+                .iter()
+                .map(|&(name, content)| (name.to_owned(), content.to_owned()))
+                .collect(),
+        );
+
+        let index_sources = sources.clone();
+        let index = move |_request: HttpRequest, _payload: Payload| {
+            let files: Vec<_> = index_sources.keys().cloned().collect();
+            future::ok(HttpResponse::Ok().json(files)).boxed_local()
+        };
+
+        let file = move |request: HttpRequest, _payload: Payload| {
+            let name = request.match_info().query("file");
+            let response = match sources.get(name) {
+                Some(content) => Ok(HttpResponse::Ok()
+                    .content_type("text/plain; charset=utf-8")
+                    .body(content.clone())),
+                None => Err(ApiError::not_found()
+                    .title("Proto source not found")
+                    .detail(format!("Proto file `{}` doesn't exist", name))
+                    .into()),
+            };
+            future::ready(response).boxed_local()
+        };
+
+        self.raw_handler(RequestHandler {
+            name: "schema".to_owned(),
+            method: HttpMethod::GET,
+            actuality: Actuality::Actual,
+            summary: String::new(),
+            description: String::new(),
+            inner: Arc::new(index) as Arc<RawHandler>,
+        });
+        self.raw_handler(RequestHandler {
+            name: "schema/{file:.*}".to_owned(),
+            method: HttpMethod::GET,
+            actuality: Actuality::Actual,
+            summary: String::new(),
+            description: String::new(),
+            inner: Arc::new(file) as Arc<RawHandler>,
+        })
+    }
+
+    /// Handles requests within the scope that match none of its endpoints.
+    pub fn fallback<F, R>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(HttpRequest, Payload) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = Result<HttpResponse, ApiError>> + 'static,
+    {
+        let index = move |request: HttpRequest, payload: Payload| {
+            let handler = handler.clone();
+            let context = RequestContext::from_request(&request);
+
+            async move { Ok(context.scope(handler(request, payload)).await?) }.boxed_local()
+        };
+        self.fallback = Some(FallbackHandler(Arc::new(index)));
+        self
+    }
+
+    /// Adds the endpoints and fallback of `other`. On a conflict, nothing is added.
+    pub fn merge(&mut self, other: Self) -> Result<&mut Self, MergeError> {
+        if other.fallback.is_some() && self.fallback.is_some() {
+            return Err(MergeError::DuplicateFallback);
+        }
+        let duplicate = other.handlers.iter().find(|handler| {
+            self.handlers
+                .iter()
+                .any(|h| h.name == handler.name && h.method == handler.method)
+        });
+        if let Some(handler) = duplicate {
+            return Err(MergeError::DuplicateEndpoint {
+                name: handler.name.clone(),
+                method: handler.method.to_string(),
+            });
+        }
+
+        if let Some(fallback) = other.fallback {
+            self.fallback = Some(fallback);
+        }
+        self.handlers.extend(other.handlers);
+        Ok(self)
+    }
+}
+
+/// Conflict between two scopes passed to `ApiScope::merge`.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum MergeError {
+    #[error("Duplicate endpoint `{name}` ({method})")]
+    DuplicateEndpoint { name: String, method: String },
+    #[error("Duplicate fallback handler")]
+    DuplicateFallback,
 }
 
 impl ApiBackend for ApiBuilder {
@@ -79,6 +352,11 @@ impl ApiBackend for ApiBuilder {
                     .to(move |request, payload| inner(request, payload)),
             );
         }
+        if let Some(FallbackHandler(ref inner)) = self.fallback {
+            let inner = inner.clone();
+            output =
+                output.default_service(web::to(move |request, payload| inner(request, payload)));
+        }
         output
     }
 }
@@ -97,68 +375,230 @@ impl ExtendApiBackend for actix_web::Scope {
 
 impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
-        let body = serde_json::to_value(&self.body).unwrap();
-        let body = if body == serde_json::json!({}) {
-            Bytes::new()
-        } else {
-            serde_json::to_string(&self.body).unwrap().into()
-        };
+        problem_json_response(self, ErrorFieldCase::SnakeCase, None)
+    }
+}
+
+fn problem_json_response(
+    error: &ApiError,
+    case: ErrorFieldCase,
+    trace_id: Option<&str>,
+) -> HttpResponse {
+    let body = ErrorBody {
+        trace_id: error
+            .body
+            .trace_id
+            .as_deref()
+            .or(trace_id)
+            .map(str::to_owned),
+        ..error.body.clone()
+    };
+    let body = match case {
+        ErrorFieldCase::SnakeCase => serde_json::to_string(&body).unwrap(),
+        _ => body.to_json(case).to_string(),
+    };
+    let body = if body == "{}" {
+        Bytes::new()
+    } else {
+        body.into()
+    };
+
+    let mut response = HttpResponse::build(error.http_code)
+        .append_header((header::CONTENT_TYPE, "application/problem+json"))
+        .body(body);
+
+    for (key, value) in error.headers.iter() {
+        response.headers_mut().append(key.clone(), value.clone());
+    }
+
+    response
+}
+
+fn json_api_error_response(error: &ApiError, trace_id: Option<&str>) -> HttpResponse {
+    let mut object = serde_json::Map::new();
+    if let Some(trace_id) = error.body.trace_id.as_deref().or(trace_id) {
+        object.insert("id".into(), trace_id.into());
+    }
+    object.insert("status".into(), error.http_code.as_u16().to_string().into());
+    if let Some(code) = error.body.error_code {
+        object.insert("code".into(), code.to_string().into());
+    }
+    if !error.body.title.is_empty() {
+        object.insert("title".into(), error.body.title.clone().into());
+    }
+    if !error.body.detail.is_empty() {
+        object.insert("detail".into(), error.body.detail.clone().into());
+    }
+    if !error.body.docs_uri.is_empty() {
+        object.insert(
+            "links".into(),
+            serde_json::json!({ "about": error.body.docs_uri }),
+        );
+    }
+    let body = serde_json::json!({ "errors": [object] });
+
+    let mut response = HttpResponse::build(error.http_code)
+        .append_header((header::CONTENT_TYPE, "application/vnd.api+json"))
+        .body(body.to_string());
+
+    for (key, value) in error.headers.iter() {
+        response.headers_mut().append(key.clone(), value.clone());
+    }
+
+    response
+}
+
+const HTTP_DATE_FORMAT: &[FormatItem<'static>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
 
-        let mut response = HttpResponse::build(self.http_code)
-            .append_header((header::CONTENT_TYPE, "application/problem+json"))
-            .body(body);
+/// Formats `date` as an HTTP date, which is always in GMT, converting it from its offset.
+fn format_http_date(date: OffsetDateTime) -> String {
+    date.to_offset(UtcOffset::UTC)
+        .format(HTTP_DATE_FORMAT)
+        .unwrap_or_default()
+}
+
+/// Returns the `If-Modified-Since` date of `GET` requests; other methods ignore it.
+fn if_modified_since(request: &HttpRequest) -> Option<OffsetDateTime> {
+    if request.method() != HttpMethod::GET {
+        return None;
+    }
+    let value = request.headers().get(header::IF_MODIFIED_SINCE)?;
+    PrimitiveDateTime::parse(value.to_str().ok()?, HTTP_DATE_FORMAT)
+        .ok()
+        .map(PrimitiveDateTime::assume_utc)
+}
 
-        for (key, value) in self.headers.iter() {
-            response.headers_mut().append(key.clone(), value.clone());
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DeprecationHeaders {
+    header: DeprecationHeader,
+    flag: bool,
+    warning_agent: Option<String>,
+}
+
+impl DeprecationHeaders {
+    pub(crate) fn new(
+        header: DeprecationHeader,
+        flag: bool,
+        warning_agent: Option<String>,
+    ) -> Self {
+        Self {
+            header,
+            flag,
+            warning_agent,
         }
+    }
 
-        response
+    fn from_request(request: &HttpRequest) -> Self {
+        request.app_data::<Self>().cloned().unwrap_or_default()
     }
 }
 
-fn json_response<T: Serialize>(actuality: Actuality, json_value: T) -> HttpResponse {
-    let mut response = HttpResponse::Ok();
+/// Serializes `json_value` as the response body, applying the status, headers and
+/// `Last-Modified` date of the wrappers around it. With the `arbitrary-precision` feature,
+/// `serde_json::Number` values are written with exactly the digits they were parsed from,
+/// so large integers and decimals round-trip; `f64` and `u64` fields are serialized as usual.
+fn json_response<T: Serialize>(
+    actuality: Actuality,
+    deprecation_headers: &DeprecationHeaders,
+    cache_control: Option<&CacheControl>,
+    if_modified_since: Option<OffsetDateTime>,
+    json_value: T,
+) -> HttpResponse {
+    let meta = response_meta::collect(&json_value);
+    let mut response = HttpResponse::build(meta.status.unwrap_or(HttpStatusCode::OK));
+
+    for header in meta.headers {
+        response.append_header(header);
+    }
+
+    if let Some(cache_control) = cache_control.filter(|c| !c.is_empty()) {
+        response.append_header((header::CACHE_CONTROL, cache_control.to_string()));
+    }
+
+    if let Some(last_modified) = meta.last_modified {
+        response.append_header((header::LAST_MODIFIED, format_http_date(last_modified)));
+    }
 
     if let Actuality::Deprecated {
-        ref discontinued_on,
+        discontinued_on,
         ref description,
+        ..
     } = actuality
     {
-        let expiration_note = match discontinued_on {
-            Some(date) => {
-                let date_format = time::format_description::parse(
-                    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT",
-                )
-                .unwrap();
-                format!(
-                    "The old API is maintained until {}.",
-                    date.format(&date_format).unwrap_or_default()
-                )
-            }
-            None => "Currently there is no specific date for disabling this endpoint.".into(),
-        };
-
-        let mut warning_text = format!(
-            "Deprecated API: This endpoint is deprecated, \
-             see the service documentation to find an alternative. \
-             {}",
-            expiration_note
-        );
+        let header = deprecation_headers.header;
+        if header != DeprecationHeader::Sunset {
+            let warning_text = deprecation_warning_text(discontinued_on, description.as_deref());
+            let agent = deprecation_headers.warning_agent.as_deref().unwrap_or("-");
+            response.append_header((header::WARNING, create_warning_header(agent, &warning_text)));
+        }
+        if let (DeprecationHeader::Sunset | DeprecationHeader::Both, Some(date)) =
+            (header, discontinued_on)
+        {
+            response.append_header((SUNSET, format_http_date(date)));
+        }
+        if deprecation_headers.flag {
+            response.append_header((DEPRECATION, "true"));
+        }
+    }
 
-        if let Some(description) = description {
-            warning_text = format!("{} Additional information: {}.", warning_text, description);
+    // `304 Not Modified` keeps the headers of the full response, except for the body ones.
+    if let (Some(last_modified), Some(since)) = (meta.last_modified, if_modified_since) {
+        if last_modified.unix_timestamp() <= since.unix_timestamp() {
+            return response.status(HttpStatusCode::NOT_MODIFIED).finish();
         }
+    }
+    if meta.no_content {
+        return response.status(HttpStatusCode::NO_CONTENT).finish();
+    }
+    if let Some((content_type, body)) = meta.binary {
+        return response.content_type(content_type).body(body);
+    }
+    response.json(json_value)
+}
 
-        let warning_string = create_warning_header(&warning_text);
+fn sunset_error(actuality: &Actuality) -> ApiError {
+    let mut error = ApiError::new(HttpStatusCode::GONE).title("Endpoint discontinued");
+    if let Actuality::Deprecated {
+        description: Some(ref description),
+        ..
+    } = *actuality
+    {
+        error = error.detail(description.clone());
+    }
+    error
+}
 
-        response.append_header((header::WARNING, warning_string));
+pub(crate) fn expiration_note(discontinued_on: Option<OffsetDateTime>) -> String {
+    match discontinued_on {
+        Some(date) => format!(
+            "The old API is maintained until {}.",
+            format_http_date(date)
+        ),
+        None => "Currently there is no specific date for disabling this endpoint.".into(),
     }
+}
 
-    response.json(json_value)
+pub(crate) fn deprecation_warning_text(
+    discontinued_on: Option<OffsetDateTime>,
+    description: Option<&str>,
+) -> String {
+    let warning_text = format!(
+        "Deprecated API: This endpoint is deprecated, \
+         see the service documentation to find an alternative. \
+         {}",
+        expiration_note(discontinued_on)
+    );
+
+    match description {
+        Some(description) => format!("{} Additional information: {}.", warning_text, description),
+        None => warning_text,
+    }
 }
 
-fn create_warning_header(warning_text: &str) -> String {
-    format!("299 - \"{}\"", warning_text)
+pub(crate) fn create_warning_header(agent: &str, warning_text: &str) -> String {
+    format!("299 {} \"{}\"", agent, warning_text)
 }
 
 impl From<EndpointMutability> for actix_web::http::Method {
@@ -170,128 +610,2635 @@ impl From<EndpointMutability> for actix_web::http::Method {
     }
 }
 
-async fn extract_query<Q>(
-    request: HttpRequest,
-    payload: Payload,
-    mutability: EndpointMutability,
-) -> Result<Q, ApiError>
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestConfig {
+    max_query_len: Option<usize>,
+    json_limit: usize,
+    decompress: bool,
+    deny_unknown_fields: bool,
+    query_format: QueryFormat,
+}
+
+impl Default for RequestConfig {
+    fn default() -> Self {
+        Self::new(
+            None,
+            DEFAULT_JSON_PAYLOAD_SIZE,
+            false,
+            false,
+            QueryFormat::default(),
+        )
+    }
+}
+
+impl RequestConfig {
+    pub(crate) fn new(
+        max_query_len: Option<usize>,
+        json_limit: usize,
+        decompress: bool,
+        deny_unknown_fields: bool,
+        query_format: QueryFormat,
+    ) -> Self {
+        Self {
+            max_query_len,
+            json_limit,
+            decompress,
+            deny_unknown_fields,
+            query_format,
+        }
+    }
+}
+
+fn content_encoding(request: &HttpRequest) -> Option<&str> {
+    request
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .filter(|encoding| !encoding.eq_ignore_ascii_case("identity"))
+}
+
+fn has_empty_body(request: &HttpRequest) -> bool {
+    let headers = request.headers();
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        return false;
+    }
+
+    match headers.get(header::CONTENT_LENGTH) {
+        Some(value) => value.as_bytes() == b"0",
+        // Only HTTP/1 requests without either header have no body; HTTP/2 frames
+        // the body without announcing its length.
+        None => request.version() < actix_web::http::Version::HTTP_2,
+    }
+}
+
+fn request_config(request: &HttpRequest) -> RequestConfig {
+    request
+        .app_data::<RequestConfig>()
+        .copied()
+        .unwrap_or_default()
+}
+
+async fn check_query_len(request: &HttpRequest) -> Result<(), ApiError> {
+    let query_len = request.query_string().len();
+    match request_config(request).max_query_len {
+        Some(max_len) if query_len > max_len => Err(ApiError::new(HttpStatusCode::URI_TOO_LONG)
+            .title("Query string is too long")
+            .detail(format!(
+                "Query string length {} exceeds the limit of {} bytes",
+                query_len, max_len
+            ))),
+        _ => Ok(()),
+    }
+}
+
+async fn parse_query_string<Q>(request: &HttpRequest) -> Result<Q, ApiError>
 where
     Q: DeserializeOwned + 'static,
 {
-    match mutability {
-        EndpointMutability::Immutable => Query::extract(&request)
-            .await
-            .map(Query::into_inner)
-            .map_err(|e| {
-                ApiError::bad_request()
-                    .title("Query parse error")
-                    .detail(e.to_string())
-            }),
+    let query_error = |e: &dyn fmt::Display, code: Option<ParseErrorCode>| {
+        ApiError::bad_request()
+            .title("Query parse error")
+            .query_parse_error_code(code)
+            .detail(e.to_string())
+    };
 
-        EndpointMutability::Mutable => Json::from_request(&request, &mut payload.into_inner())
+    match request_config(request).query_format {
+        QueryFormat::Nested => {
+            let query = index_repeated_keys(request.query_string());
+            serde_qs::Config::new(NESTED_QUERY_DEPTH, false)
+                .deserialize_str(&query)
+                .map_err(|e| query_error(&e, ParseErrorCode::from_message(&e.to_string())))
+        }
+        _ => Query::extract(request)
             .await
-            .map(Json::into_inner)
+            .map(Query::into_inner)
             .map_err(|e| {
-                ApiError::bad_request()
-                    .title("JSON body parse error")
-                    .detail(e.to_string())
+                // The payload error prefixes the message, so the serde error is classified.
+                // Any flat query string is well-formed, and values that fail to parse
+                // (e.g. `page=first` for a number) are reported with custom messages.
+                let code = match e.as_error::<QueryPayloadError>() {
+                    Some(QueryPayloadError::Deserialize(e)) => {
+                        ParseErrorCode::from_message(&e.to_string())
+                            .or(Some(ParseErrorCode::TypeMismatch))
+                    }
+                    _ => None,
+                };
+                query_error(&e, code)
             }),
     }
 }
 
-impl<Q, I, F, R> From<NamedWith<Q, I, R, F>> for RequestHandler
-where
-    F: Fn(Q) -> R + 'static + Clone + Send + Sync,
-    Q: DeserializeOwned + 'static,
-    I: Serialize + 'static,
-    R: Future<Output = Result<I, crate::Error>>,
-{
-    fn from(f: NamedWith<Q, I, R, F>) -> Self {
-        let handler = f.inner.handler;
-        let actuality = f.inner.actuality;
-        let mutability = f.mutability;
-        let index = move |request: HttpRequest, payload: Payload| {
-            let handler = handler.clone();
-            let actuality = actuality.clone();
+const NESTED_QUERY_DEPTH: usize = 5;
 
-            async move {
-                let query = extract_query(request, payload, mutability).await?;
-                let response = handler(query).await?;
-                Ok(json_response(actuality, response))
+/// Rewrites repeated plain keys (`id=1&id=2`) into indexed ones (`id[0]=1&id[1]=2`).
+fn index_repeated_keys(query: &str) -> String {
+    let pairs: Vec<_> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for &(key, _) in &pairs {
+        *counts.entry(key).or_default() += 1;
+    }
+
+    let mut indices: HashMap<&str, usize> = HashMap::new();
+    pairs
+        .iter()
+        .map(|&(key, value)| {
+            if key.contains('[') || counts[key] == 1 {
+                return format!("{}={}", key, value);
             }
-            .boxed_local()
-        };
+            let index = indices.entry(key).or_default();
+            let pair = format!("{}[{}]={}", key, index, value);
+            *index += 1;
+            pair
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
 
-        Self {
-            name: f.name,
-            method: f.mutability.into(),
-            inner: Arc::from(index) as Arc<RawHandler>,
-        }
+fn has_json_content_type(request: &HttpRequest) -> bool {
+    match request.mime_type() {
+        Ok(Some(mime)) => mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON),
+        _ => false,
     }
 }
 
-impl From<&AllowOrigin> for Cors {
-    fn from(origin: &AllowOrigin) -> Self {
-        match *origin {
-            AllowOrigin::Any => Cors::default(),
-            AllowOrigin::Whitelist(ref hosts) => {
-                let mut cors = Cors::default();
-                for host in hosts {
-                    cors = cors.allowed_origin(host);
-                }
+async fn check_content_encoding(
+    request: &HttpRequest,
+    config: RequestConfig,
+) -> Result<(), ApiError> {
+    match content_encoding(request).filter(|_| !config.decompress) {
+        Some(encoding) => Err(ApiError::new(HttpStatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .title("Unsupported content encoding")
+            .detail(format!(
+                "Request body encoding `{}` is not accepted by this server",
+                encoding
+            ))),
+        None => Ok(()),
+    }
+}
 
-                cors
-            }
+async fn check_json_content_type(request: &HttpRequest) -> Result<(), ApiError> {
+    if has_json_content_type(request) {
+        Ok(())
+    } else {
+        Err(ApiError::new(HttpStatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .title("Unsupported content type")
+            .detail("Request body must have the `application/json` content type"))
+    }
+}
+
+/// Buffers a request body, rejecting it as soon as it exceeds `limit` bytes.
+async fn read_body<P>(mut payload: P, limit: usize) -> Result<Bytes, ApiError>
+where
+    P: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| {
+            ApiError::bad_request()
+                .title("Request body read error")
+                .detail(e.to_string())
+        })?;
+        if body.len() + chunk.len() > limit {
+            return Err(ApiError::new(HttpStatusCode::PAYLOAD_TOO_LARGE)
+                .title("Request body is too large")
+                .detail(format!("Request body exceeds the limit of {} bytes", limit)));
         }
+        body.extend_from_slice(&chunk);
     }
+    Ok(body.freeze())
 }
 
-impl From<AllowOrigin> for Cors {
-    fn from(origin: AllowOrigin) -> Self {
-        Self::from(&origin)
+/// Parses a JSON body of a mutable endpoint, keeping its exact (decompressed) bytes.
+async fn extract_body<Q>(request: &HttpRequest, payload: Payload) -> Result<(Q, Bytes), ApiError>
+where
+    Q: DeserializeOwned + 'static,
+{
+    let config = request_config(request);
+    check_query_len(request).await?;
+    check_content_encoding(request, config).await?;
+    if !has_empty_body(request) {
+        check_json_content_type(request).await?;
     }
+
+    let payload = Decompress::from_headers(payload.into_inner(), request.headers());
+    let body = read_body(payload, config.json_limit).await?;
+    let query = if body.is_empty() {
+        serde_json::from_value(serde_json::Value::Null)
+    } else {
+        serde_json::from_slice(&body)
+    };
+    let query = query.map_err(|e| json_parse_error(&e, ParseErrorCode::from_json(&e)))?;
+    Ok((query, body))
 }
 
-trait ErrorHandlersEx {
-    fn default_api_error<F: Fn(&ServiceResponse<EitherBody<BoxBody>>) -> ApiError + 'static>(
-        self,
-        status: HttpStatusCode,
-        handler: F,
-    ) -> Self;
+fn json_parse_error(e: &dyn fmt::Display, code: Option<ParseErrorCode>) -> ApiError {
+    ApiError::bad_request()
+        .title("JSON body parse error")
+        .detail(e.to_string())
+        .parse_error_code(code)
 }
 
-impl ErrorHandlersEx for ErrorHandlers<EitherBody<BoxBody>> {
-    fn default_api_error<F: Fn(&ServiceResponse<EitherBody<BoxBody>>) -> ApiError + 'static>(
-        self,
-        status: HttpStatusCode,
-        handler: F,
-    ) -> Self {
-        self.handler(status, move |res| {
-            let res = match res.response().body().size() {
-                BodySize::None | BodySize::Sized(0) | BodySize::Stream => {
-                    let error: actix_web::Error = handler(&res).into();
-                    res.into_response(error.as_response_error().error_response())
-                        .map_into_left_body()
-                }
-                _ => res,
+/// Reads a JSON body regardless of its content type, which is validated by the caller.
+async fn read_json<T>(request: &HttpRequest, payload: Payload, limit: usize) -> Result<T, ApiError>
+where
+    T: DeserializeOwned + 'static,
+{
+    JsonBody::new(request, &mut payload.into_inner(), None, false)
+        .limit(limit)
+        .await
+        .map_err(|e| {
+            let code = match e {
+                JsonPayloadError::Deserialize(ref e) => ParseErrorCode::from_json(e),
+                _ => None,
             };
-
-            Ok(ErrorHandlerResponse::Response(res.map_into_left_body()))
+            json_parse_error(&e, code)
         })
-    }
 }
 
-pub(crate) fn error_handlers() -> ErrorHandlers<EitherBody<BoxBody>> {
-    ErrorHandlers::new()
-        .default_api_error(HttpStatusCode::NOT_FOUND, |res| {
+async fn extract_query<Q>(
+    request: HttpRequest,
+    payload: Payload,
+    mutability: EndpointMutability,
+    accept_get_body: bool,
+    lenient_content_type: bool,
+) -> Result<Q, ApiError>
+where
+    Q: DeserializeOwned + 'static,
+{
+    let config = request_config(&request);
+    check_query_len(&request).await?;
+
+    match mutability {
+        EndpointMutability::Immutable => {
+            if accept_get_body && !has_empty_body(&request) {
+                let body = Json::<Q>::from_request(&request, &mut payload.into_inner()).await;
+                if let Ok(body) = body {
+                    return Ok(body.into_inner());
+                }
+            }
+
+            parse_query_string(&request).await
+        }
+
+        EndpointMutability::Mutable => {
+            check_content_encoding(&request, config).await?;
+
+            if has_empty_body(&request) {
+                if let Ok(query) = serde_json::from_value(serde_json::Value::Null) {
+                    return Ok(query);
+                }
+            }
+
+            if !lenient_content_type {
+                check_json_content_type(&request).await?;
+            }
+
+            if !config.deny_unknown_fields {
+                return read_json(&request, payload, config.json_limit).await;
+            }
+
+            let value: serde_json::Value = read_json(&request, payload, config.json_limit).await?;
+            let mut unknown_fields = Vec::new();
+            let query = serde_ignored::deserialize(value, |path| {
+                unknown_fields.push(path.to_string());
+            })
+            .map_err(|e| json_parse_error(&e, ParseErrorCode::from_json(&e)))?;
+
+            if unknown_fields.is_empty() {
+                Ok(query)
+            } else {
+                Err(ApiError::bad_request()
+                    .title("Unknown JSON fields")
+                    .error_code(ParseErrorCode::UnknownField as u8)
+                    .detail(format!(
+                        "Request body contains unknown fields: {}",
+                        unknown_fields.join(", ")
+                    )))
+            }
+        }
+    }
+}
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+fn request_id(request: &HttpRequest) -> Option<&str> {
+    request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+}
+
+#[cfg(feature = "tracing")]
+fn request_span(endpoint: &str, request: &HttpRequest) -> tracing::Span {
+    let request_id = request_id(request).unwrap_or_default();
+    tracing::info_span!(
+        "api_request",
+        endpoint,
+        method = %request.method(),
+        request_id
+    )
+}
+
+/// Logs a panic of an endpoint handler, which is reported to the client as a plain
+/// internal error without the panic message.
+fn handler_panic_error(
+    endpoint: &str,
+    request_id: Option<&str>,
+    panic: &(dyn Any + Send),
+) -> ApiError {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause");
+    log::error!(
+        "Handler of endpoint `{}` panicked (request id: {}): {}",
+        endpoint,
+        request_id.unwrap_or("-"),
+        message
+    );
+    ApiError::internal("Endpoint handler panicked")
+}
+
+/// Logs a request whose future is dropped before it completes, which is how a client
+/// disconnect surfaces; it is not a server failure.
+struct DisconnectGuard {
+    endpoint: Arc<str>,
+    request_id: Option<String>,
+    completed: bool,
+}
+
+impl DisconnectGuard {
+    fn new(endpoint: Arc<str>, request_id: Option<String>) -> Self {
+        Self {
+            endpoint,
+            request_id,
+            completed: false,
+        }
+    }
+
+    fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if !self.completed {
+            log::debug!(
+                "Client disconnected before endpoint `{}` responded (request id: {})",
+                self.endpoint,
+                self.request_id.as_deref().unwrap_or("-")
+            );
+        }
+    }
+}
+
+impl<Q, I, F, R> From<NamedWith<Q, I, R, F>> for RequestHandler
+where
+    F: Fn(Q) -> R + 'static + Clone + Send + Sync,
+    Q: DeserializeOwned + 'static,
+    I: Serialize + 'static,
+    R: Future<Output = Result<I, crate::Error>>,
+{
+    fn from(f: NamedWith<Q, I, R, F>) -> Self {
+        let handler = f.inner.handler;
+        let actuality = f.inner.actuality.clone();
+        let cache_control = f.inner.cache_control;
+        let accept_get_body = f.inner.accept_get_body;
+        let lenient_content_type = f.inner.lenient_content_type;
+        let required_headers = f.inner.required_headers;
+        let circuit_breaker = f.inner.circuit_breaker;
+        let api_key_auth = f.inner.api_key_auth;
+        #[cfg(debug_assertions)]
+        let schema_check = f.inner.output_schema.map(SchemaCheck::new);
+        let mutability = f.mutability;
+        let endpoint: Arc<str> = Arc::from(f.name.as_str());
+        let index = move |request: HttpRequest, payload: Payload| {
+            if let Some(ref auth) = api_key_auth {
+                if !auth.is_authorized(request.headers()) {
+                    return future::err(auth.error().into()).boxed_local();
+                }
+            }
+
+            let handler = handler.clone();
+            let endpoint = Arc::clone(&endpoint);
+            let actuality = actuality.clone();
+            let cache_control = cache_control.clone();
+            let circuit_breaker = circuit_breaker.clone();
+            #[cfg(debug_assertions)]
+            let schema_check = schema_check.clone();
+            let request_id = request_id(&request).map(str::to_owned);
+            let if_modified_since = if_modified_since(&request);
+            let context = RequestContext::from_request(&request);
+            let deprecation_headers = DeprecationHeaders::from_request(&request);
+            let missing_header = required_headers
+                .iter()
+                .find(|name| !request.headers().contains_key(name.as_str()))
+                .cloned();
+            #[cfg(feature = "tracing")]
+            let span = request_span(&endpoint, &request);
+            let disconnect_guard = DisconnectGuard::new(Arc::clone(&endpoint), request_id.clone());
+
+            let future = async move {
+                if actuality.is_sunset(OffsetDateTime::now_utc()) {
+                    return Err(sunset_error(&actuality).into());
+                }
+
+                if let Some(name) = missing_header {
+                    return Err(ApiError::bad_request()
+                        .title("Missing required header")
+                        .detail(format!("Request must include the `{}` header", name))
+                        .into());
+                }
+
+                let query = extract_query(
+                    request,
+                    payload,
+                    mutability,
+                    accept_get_body,
+                    lenient_content_type,
+                )
+                .await?;
+                let permit = match circuit_breaker {
+                    Some(ref breaker) => match breaker.try_acquire() {
+                        Some(permit) => Some(permit),
+                        None => return Err(breaker.error().into()),
+                    },
+                    None => None,
+                };
+
+                let response = AssertUnwindSafe(context.scope(async { handler(query).await }))
+                    .catch_unwind()
+                    .await;
+                let response = match response {
+                    Ok(response) => response,
+                    Err(panic) => Err(handler_panic_error(
+                        &endpoint,
+                        request_id.as_deref(),
+                        &*panic,
+                    )),
+                };
+                if let (Some(ref breaker), Some(permit)) = (&circuit_breaker, permit) {
+                    let is_failure = match response {
+                        Err(ref e) => e.http_code.is_server_error(),
+                        Ok(_) => false,
+                    };
+                    breaker.record(permit, is_failure);
+                }
+                #[cfg(feature = "tracing")]
+                if let Err(ref e) = response {
+                    tracing::warn!(status = %e.http_code, error = %e, "Endpoint handler failed");
+                }
+                let response = response?;
+                #[cfg(debug_assertions)]
+                if let Some(ref schema_check) = schema_check {
+                    schema_check.check(&endpoint, &response);
+                }
+                Ok(json_response(
+                    actuality,
+                    &deprecation_headers,
+                    cache_control.as_ref(),
+                    if_modified_since,
+                    response,
+                ))
+            };
+            let future = async move {
+                let response = future.await;
+                disconnect_guard.complete();
+                response
+            };
+            #[cfg(feature = "tracing")]
+            let future = tracing::Instrument::instrument(future, span);
+            future.boxed_local()
+        };
+
+        Self {
+            name: f.name,
+            method: f.mutability.into(),
+            actuality: f.inner.actuality,
+            summary: f.inner.summary,
+            description: f.inner.description,
+            inner: Arc::from(index) as Arc<RawHandler>,
+        }
+    }
+}
+
+impl From<&AllowOrigin> for Cors {
+    fn from(origin: &AllowOrigin) -> Self {
+        match *origin {
+            AllowOrigin::Any => Cors::default(),
+            AllowOrigin::Whitelist(ref hosts) => {
+                let mut cors = Cors::default();
+                for host in hosts {
+                    cors = cors.allowed_origin(host);
+                }
+
+                cors
+            }
+        }
+    }
+}
+
+impl From<AllowOrigin> for Cors {
+    fn from(origin: AllowOrigin) -> Self {
+        Self::from(&origin)
+    }
+}
+
+trait ErrorHandlersEx<B> {
+    /// Replaces `status` responses that carry no body with the given API error; responses
+    /// with a body are passed through unchanged.
+    fn default_api_error<F: Fn(&ServiceResponse<B>) -> ApiError + 'static>(
+        self,
+        status: HttpStatusCode,
+        handler: F,
+    ) -> Self;
+}
+
+impl<B: MessageBody + 'static> ErrorHandlersEx<B> for ErrorHandlers<B> {
+    fn default_api_error<F: Fn(&ServiceResponse<B>) -> ApiError + 'static>(
+        self,
+        status: HttpStatusCode,
+        handler: F,
+    ) -> Self {
+        self.handler(status, move |res| {
+            let res = match res.response().body().size() {
+                BodySize::None | BodySize::Sized(0) | BodySize::Stream => {
+                    let error: actix_web::Error = handler(&res).into();
+                    res.into_response(HttpResponse::from_error(error))
+                        .map_into_right_body()
+                }
+                _ => res.map_into_left_body(),
+            };
+
+            Ok(ErrorHandlerResponse::Response(res))
+        })
+    }
+}
+
+pub(crate) fn error_handlers<B: MessageBody + 'static>(raw_statuses: &[u16]) -> ErrorHandlers<B> {
+    let is_rewritten = |status: HttpStatusCode| !raw_statuses.contains(&status.as_u16());
+    let mut handlers = ErrorHandlers::new();
+
+    if is_rewritten(HttpStatusCode::NOT_FOUND) {
+        handlers = handlers.default_api_error(HttpStatusCode::NOT_FOUND, |res| {
             ApiError::not_found()
                 .title("Method not found")
                 .detail(format!(
                     "API endpoint `{}` doesn't exist",
                     res.request().uri().path()
                 ))
-        })
-        .default_api_error(HttpStatusCode::BAD_REQUEST, |_res| {
+        });
+    }
+    if is_rewritten(HttpStatusCode::BAD_REQUEST) {
+        handlers = handlers.default_api_error(HttpStatusCode::BAD_REQUEST, |_res| {
             ApiError::bad_request().title("Bad request")
+        });
+    }
+    handlers
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MaintenanceGuard {
+    mode: MaintenanceMode,
+}
+
+impl MaintenanceGuard {
+    pub(crate) fn new(mode: MaintenanceMode) -> Self {
+        Self { mode }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MaintenanceGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = MaintenanceGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(MaintenanceGuardMiddleware {
+            service,
+            mode: self.mode.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MaintenanceGuardMiddleware<S> {
+    service: S,
+    mode: MaintenanceMode,
+}
+
+impl<S, B> Service<ServiceRequest> for MaintenanceGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        if self.mode.rejects(request.path()) {
+            let response = HttpResponse::from_error(self.mode.error());
+            return future::ok(request.into_response(response).map_into_right_body()).boxed_local();
+        }
+
+        self.service
+            .call(request)
+            .map_ok(ServiceResponse::map_into_left_body)
+            .boxed_local()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ErrorFormatter {
+    format: ErrorFormat,
+    field_case: ErrorFieldCase,
+}
+
+impl ErrorFormatter {
+    pub(crate) fn new(format: ErrorFormat, field_case: ErrorFieldCase) -> Self {
+        Self { format, field_case }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ErrorFormatter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ErrorFormatterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ErrorFormatterMiddleware {
+            service,
+            format: self.format,
+            field_case: self.field_case,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ErrorFormatterMiddleware<S> {
+    service: S,
+    format: ErrorFormat,
+    field_case: ErrorFieldCase,
+}
+
+impl<S, B> Service<ServiceRequest> for ErrorFormatterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let format = self.format;
+        let field_case = self.field_case;
+        self.service
+            .call(request)
+            .map_ok(move |res| {
+                let api_error = res
+                    .response()
+                    .error()
+                    .and_then(|e| e.as_error::<ApiError>());
+                let trace_id = request_id(res.request())
+                    .filter(|_| api_error.is_some_and(|e| e.body.trace_id.is_none()));
+                let response = match (format, api_error) {
+                    (ErrorFormat::JsonApi, Some(error)) => {
+                        Some(json_api_error_response(error, trace_id))
+                    }
+                    (ErrorFormat::ProblemJson, Some(error))
+                        if field_case != ErrorFieldCase::SnakeCase || trace_id.is_some() =>
+                    {
+                        Some(problem_json_response(error, field_case, trace_id))
+                    }
+                    _ => None,
+                };
+                match response {
+                    Some(mut response) => {
+                        // Keep headers set by other middleware, such as CORS or rate limit ones.
+                        let rebuilt: Vec<_> = response.headers().keys().cloned().collect();
+                        for (name, value) in res.headers() {
+                            if !rebuilt.contains(name) && *name != header::CONTENT_LENGTH {
+                                response.headers_mut().append(name.clone(), value.clone());
+                            }
+                        }
+                        res.into_response(response).map_into_right_body()
+                    }
+                    None => res.map_into_left_body(),
+                }
+            })
+            .boxed_local()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ApiKeyGuard {
+    auth: Option<ApiKeyAuth>,
+}
+
+impl ApiKeyGuard {
+    pub(crate) fn new(auth: Option<ApiKeyAuth>) -> Self {
+        Self { auth }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeyGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ApiKeyGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ApiKeyGuardMiddleware {
+            service,
+            auth: self.auth.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ApiKeyGuardMiddleware<S> {
+    service: S,
+    auth: Option<ApiKeyAuth>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeyGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        if let Some(ref auth) = self.auth {
+            // Browsers send preflight requests without credentials.
+            if request.method() != HttpMethod::OPTIONS && !auth.is_authorized(request.headers()) {
+                let response = HttpResponse::from_error(auth.error());
+                return future::ok(request.into_response(response).map_into_right_body())
+                    .boxed_local();
+            }
+        }
+
+        self.service
+            .call(request)
+            .map_ok(ServiceResponse::map_into_left_body)
+            .boxed_local()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IdempotencyGuard {
+    idempotency: Option<Idempotency>,
+}
+
+impl IdempotencyGuard {
+    pub(crate) fn new(idempotency: Option<Idempotency>) -> Self {
+        Self { idempotency }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for IdempotencyGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = IdempotencyGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(IdempotencyGuardMiddleware {
+            service,
+            idempotency: self.idempotency.clone(),
         })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct IdempotencyGuardMiddleware<S> {
+    service: S,
+    idempotency: Option<Idempotency>,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let idempotency = match self.idempotency {
+            Some(ref idempotency) if request.method() == HttpMethod::POST => idempotency.clone(),
+            _ => {
+                return self
+                    .service
+                    .call(request)
+                    .map_ok(ServiceResponse::map_into_left_body)
+                    .boxed_local()
+            }
+        };
+        let key = match request.headers().get(IDEMPOTENCY_KEY) {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                return self
+                    .service
+                    .call(request)
+                    .map_ok(ServiceResponse::map_into_left_body)
+                    .boxed_local()
+            }
+        };
+
+        match idempotency.begin(&idempotency_client(&request), request.path(), &key) {
+            Err(IdempotencyState::Completed(cached)) => {
+                let response = cached.to_response();
+                future::ok(request.into_response(response).map_into_right_body()).boxed_local()
+            }
+            Err(_) => {
+                let response = HttpResponse::from_error(idempotency.conflict_error());
+                future::ok(request.into_response(response).map_into_right_body()).boxed_local()
+            }
+            Ok(pending) => {
+                let response = self.service.call(request);
+                // `pending` releases the key if the response fails or this future is dropped.
+                async move {
+                    let (request, response) = response.await?.into_parts();
+                    let (head, body) = response.into_parts();
+                    let body = body::to_bytes(body).await.map_err(|_| {
+                        actix_web::error::ErrorInternalServerError("Unable to read response body")
+                    })?;
+
+                    // Server errors are not cached so that a retry gets another chance.
+                    if !head.status().is_server_error() {
+                        // CORS headers depend on the request origin and are added to replayed
+                        // responses anew.
+                        let mut headers = head.headers().clone();
+                        headers.retain(|name, _| !name.as_str().starts_with("access-control-"));
+                        pending.complete(CachedResponse {
+                            status: head.status(),
+                            headers,
+                            body: body.clone(),
+                        });
+                    }
+
+                    let response = head.set_body(BoxBody::new(body));
+                    Ok(ServiceResponse::new(request, response).map_into_right_body())
+                }
+                .boxed_local()
+            }
+        }
+    }
+}
+
+fn idempotency_client(request: &ServiceRequest) -> Vec<u8> {
+    if let Some(credentials) = request.headers().get(header::AUTHORIZATION) {
+        return [b"authorization ", credentials.as_bytes()].concat();
+    }
+    let context = RequestContext::from_request(request.request());
+    #[cfg(feature = "tls")]
+    if let Some(identity) = context.client_identity() {
+        return format!("tls {}", identity.subject()).into_bytes();
+    }
+    match context.client_ip() {
+        Some(ip) => format!("ip {}", ip).into_bytes(),
+        None => Vec::new(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LoadShedGuard {
+    limit: Option<usize>,
+    retry_after: Option<u64>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl LoadShedGuard {
+    pub(crate) fn new(
+        limit: Option<usize>,
+        retry_after: Option<u64>,
+        in_flight: Arc<AtomicUsize>,
+    ) -> Self {
+        Self {
+            limit,
+            retry_after,
+            in_flight,
+        }
+    }
+
+    fn error(&self) -> ApiError {
+        let error = ApiError::new(HttpStatusCode::SERVICE_UNAVAILABLE)
+            .title("Server is overloaded")
+            .detail("Too many requests are being processed, try again later");
+        match self.retry_after {
+            Some(retry_after) => error.header(header::RETRY_AFTER, &retry_after.to_string()),
+            None => error,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LoadShedGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = LoadShedGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(LoadShedGuardMiddleware {
+            service,
+            guard: self.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LoadShedGuardMiddleware<S> {
+    service: S,
+    guard: LoadShedGuard,
+}
+
+/// Counts a request as in flight until dropped.
+#[derive(Debug)]
+struct InFlightRequest(Arc<AtomicUsize>);
+
+impl InFlightRequest {
+    fn acquire(counter: &Arc<AtomicUsize>) -> (Self, usize) {
+        let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        (Self(Arc::clone(counter)), count)
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for LoadShedGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let limit = match self.guard.limit {
+            Some(limit) => limit,
+            None => {
+                return self
+                    .service
+                    .call(request)
+                    .map_ok(ServiceResponse::map_into_left_body)
+                    .boxed_local()
+            }
+        };
+
+        let (in_flight, count) = InFlightRequest::acquire(&self.guard.in_flight);
+        if count > limit {
+            drop(in_flight);
+            let response = HttpResponse::from_error(self.guard.error());
+            return future::ok(request.into_response(response).map_into_right_body()).boxed_local();
+        }
+
+        self.service
+            .call(request)
+            .map_ok(ServiceResponse::map_into_left_body)
+            .map(move |response| {
+                drop(in_flight);
+                response
+            })
+            .boxed_local()
+    }
+}
+
+/// Marks responses that must not be compressed with `Content-Encoding: identity`, which
+/// makes the outer `Compress` middleware pass them through untouched.
+#[derive(Debug, Clone)]
+pub(crate) struct CompressionPolicy {
+    config: Arc<CompressionConfig>,
+}
+
+impl CompressionPolicy {
+    pub(crate) fn new(config: CompressionConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CompressionPolicy
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = CompressionPolicyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(CompressionPolicyMiddleware {
+            service,
+            config: Arc::clone(&self.config),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CompressionPolicyMiddleware<S> {
+    service: S,
+    config: Arc<CompressionConfig>,
+}
+
+impl<S, B> Service<ServiceRequest> for CompressionPolicyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let min_size = self.config.min_size;
+
+        self.service
+            .call(request)
+            .map_ok(move |mut res| {
+                let too_small = match res.response().body().size() {
+                    BodySize::Sized(size) => size < min_size as u64,
+                    BodySize::None => true,
+                    BodySize::Stream => false,
+                };
+                let compressed = res
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(is_compressed_content_type);
+                let headers = res.headers_mut();
+                if (too_small || compressed) && !headers.contains_key(header::CONTENT_ENCODING) {
+                    headers.insert(
+                        header::CONTENT_ENCODING,
+                        HeaderValue::from_static("identity"),
+                    );
+                }
+                res
+            })
+            .boxed_local()
+    }
+}
+
+pub(crate) const API_VERSION: HeaderName = HeaderName::from_static("x-api-version");
+
+/// Sends the deploy-scoped version tag in `X-Api-Version` and prefixes it to the `ETag`
+/// of responses, so that a new deploy changes every ETag.
+#[derive(Debug, Clone)]
+pub(crate) struct VersionTag {
+    tag: Arc<str>,
+}
+
+impl VersionTag {
+    pub(crate) fn new(tag: &str) -> Self {
+        Self { tag: tag.into() }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for VersionTag
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = VersionTagMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(VersionTagMiddleware {
+            service,
+            tag: Arc::clone(&self.tag),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct VersionTagMiddleware<S> {
+    service: S,
+    tag: Arc<str>,
+}
+
+impl<S, B> Service<ServiceRequest> for VersionTagMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let tag = Arc::clone(&self.tag);
+
+        self.service
+            .call(request)
+            .map_ok(move |mut res| {
+                let headers = res.headers_mut();
+                if !headers.contains_key(&API_VERSION) {
+                    if let Ok(value) = HeaderValue::from_str(&tag) {
+                        headers.insert(API_VERSION, value);
+                    }
+                }
+                let etag = headers
+                    .get(header::ETAG)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|etag| versioned_etag(&tag, etag));
+                if let Some(etag) = etag {
+                    headers.insert(header::ETAG, etag);
+                }
+                res
+            })
+            .boxed_local()
+    }
+}
+
+/// `"abc"` becomes `"{tag}-abc"`, keeping the weak `W/` prefix.
+fn versioned_etag(tag: &str, etag: &str) -> Option<HeaderValue> {
+    let (weak, opaque) = match etag.strip_prefix("W/") {
+        Some(opaque) => ("W/", opaque),
+        None => ("", etag),
+    };
+    let opaque = opaque.strip_prefix('"')?.strip_suffix('"')?;
+    HeaderValue::from_str(&format!("{}\"{}-{}\"", weak, tag, opaque)).ok()
+}
+
+/// Removes encodings that are not allowed by the compression config from the
+/// `Accept-Encoding` request header, so that the outer `Compress` middleware never
+/// chooses them.
+#[derive(Debug, Clone)]
+pub(crate) struct AcceptEncodingFilter {
+    encodings: Arc<[String]>,
+}
+
+impl AcceptEncodingFilter {
+    pub(crate) fn new(encodings: &[String]) -> Self {
+        Self {
+            encodings: encodings.into(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AcceptEncodingFilter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = AcceptEncodingFilterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(AcceptEncodingFilterMiddleware {
+            service,
+            encodings: Arc::clone(&self.encodings),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AcceptEncodingFilterMiddleware<S> {
+    service: S,
+    encodings: Arc<[String]>,
+}
+
+impl<S, B> Service<ServiceRequest> for AcceptEncodingFilterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let accepted = filter_accept_encoding(request.headers(), &self.encodings);
+        let headers = request.headers_mut();
+        match accepted.and_then(|value| HeaderValue::from_str(&value).ok()) {
+            Some(value) => {
+                headers.insert(header::ACCEPT_ENCODING, value);
+            }
+            None => {
+                headers.remove(header::ACCEPT_ENCODING);
+            }
+        }
+        self.service.call(request)
+    }
+}
+
+/// Returns `Accept-Encoding` with only `identity` and the `allowed` codings; `*` is
+/// replaced by the allowed codings the client has not listed explicitly.
+fn filter_accept_encoding(headers: &header::HeaderMap, allowed: &[String]) -> Option<String> {
+    let items: Vec<(&str, Option<&str>)> = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|item| match item.split_once(';') {
+            Some((coding, params)) => (coding.trim(), Some(params.trim())),
+            None => (item.trim(), None),
+        })
+        .filter(|(coding, _)| !coding.is_empty())
+        .collect();
+    let is_listed = |coding: &str| {
+        items
+            .iter()
+            .any(|(listed, _)| listed.eq_ignore_ascii_case(coding))
+    };
+    let with_params = |coding: &str, params: Option<&str>| match params {
+        Some(params) => format!("{};{}", coding, params),
+        None => coding.to_owned(),
+    };
+
+    let mut accepted = Vec::new();
+    for &(coding, params) in &items {
+        if coding == "*" {
+            accepted.extend(
+                allowed
+                    .iter()
+                    .filter(|allowed| !is_listed(allowed))
+                    .map(|allowed| with_params(allowed, params)),
+            );
+        } else if coding.eq_ignore_ascii_case("identity")
+            || allowed
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(coding))
+        {
+            accepted.push(with_params(coding, params));
+        }
+    }
+    (!accepted.is_empty()).then(|| accepted.join(", "))
+}
+
+fn is_compressed_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.split_once('/') {
+        Some(("image", subtype)) => subtype != "svg+xml",
+        Some(("audio", _)) | Some(("video", _)) => true,
+        _ => matches!(
+            essence.as_str(),
+            "application/gzip"
+                | "application/x-gzip"
+                | "application/zip"
+                | "application/zstd"
+                | "application/x-7z-compressed"
+                | "font/woff"
+                | "font/woff2"
+        ),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BodyLogger {
+    config: Arc<BodyLogging>,
+    limit: usize,
+}
+
+impl BodyLogger {
+    pub(crate) fn new(config: BodyLogging, limit: usize) -> Self {
+        Self {
+            config: Arc::new(config),
+            limit,
+        }
+    }
+
+    fn format_body(&self, body: &[u8]) -> String {
+        let mut value: serde_json::Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(_) if body.is_empty() => return "<empty>".to_owned(),
+            Err(_) => return format!("<{} bytes, not JSON>", body.len()),
+        };
+        for pointer in &self.config.redacted {
+            if let Some(redacted) = value.pointer_mut(pointer) {
+                *redacted = "<redacted>".into();
+            }
+        }
+        value.to_string()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BodyLogger
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = BodyLoggerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(BodyLoggerMiddleware {
+            service: Rc::new(service),
+            logger: self.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BodyLoggerMiddleware<S> {
+    service: Rc<S>,
+    logger: BodyLogger,
+}
+
+impl<S, B> Service<ServiceRequest> for BodyLoggerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let is_logged = log::log_enabled!(log::Level::Debug)
+            && self
+                .logger
+                .config
+                .paths
+                .iter()
+                .any(|path| path == request.path());
+        if !is_logged {
+            return self
+                .service
+                .call(request)
+                .map_ok(ServiceResponse::map_into_boxed_body)
+                .boxed_local();
+        }
+
+        let service = Rc::clone(&self.service);
+        let logger = self.logger.clone();
+        async move {
+            let endpoint = format!("{} {}", request.method(), request.path());
+            let request_len = request
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+            match request_len {
+                Some(len) if len <= logger.limit => {
+                    let body = read_body(request.take_payload(), logger.limit).await?;
+                    log::debug!("{} request body: {}", endpoint, logger.format_body(&body));
+                    request.set_payload(actix_web::dev::Payload::Stream {
+                        payload: Box::pin(stream::once(future::ok(body))),
+                    });
+                }
+                _ => log::debug!("{} request body is not logged", endpoint),
+            }
+
+            let response = service.call(request).await?;
+            let response = match response.response().body().size() {
+                BodySize::Sized(len) if len <= logger.limit as u64 => {
+                    let (request, response) = response.into_parts();
+                    let (head, body) = response.into_parts();
+                    let body = body::to_bytes(body).await.map_err(|_| {
+                        actix_web::error::ErrorInternalServerError("Unable to read response body")
+                    })?;
+                    log::debug!("{} response body: {}", endpoint, logger.format_body(&body));
+                    ServiceResponse::new(request, head.set_body(BoxBody::new(body)))
+                }
+                _ => {
+                    log::debug!("{} response body is not logged", endpoint);
+                    response.map_into_boxed_body()
+                }
+            };
+            Ok(response)
+        }
+        .boxed_local()
+    }
+}
+
+/// Applies the CORS policy of the longest path prefix matching the request, or the
+/// default policy if no prefix matches. Prefixes match whole path segments only.
+#[derive(Debug)]
+pub(crate) struct ScopedCors {
+    default: Cors,
+    scoped: Vec<(String, Cors)>,
+}
+
+impl ScopedCors {
+    pub(crate) fn new(default: Cors, scoped: impl IntoIterator<Item = (String, Cors)>) -> Self {
+        let mut scoped: Vec<_> = scoped
+            .into_iter()
+            .map(|(prefix, cors)| (prefix.trim_end_matches('/').to_owned(), cors))
+            .collect();
+        scoped.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { default, scoped }
+    }
+}
+
+fn matches_path_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Lets several middlewares wrap the same service.
+#[derive(Debug)]
+pub(crate) struct SharedService<S>(Rc<S>);
+
+impl<S, Req> Service<Req> for SharedService<S>
+where
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&self, request: Req) -> Self::Future {
+        self.0.call(request)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ScopedCors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ScopedCorsMiddleware<S>;
+    type InitError = ();
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let service = Rc::new(service);
+        let default = self
+            .default
+            .new_transform(SharedService(Rc::clone(&service)));
+        let scoped = self
+            .scoped
+            .iter()
+            .map(|(prefix, cors)| {
+                let prefix = prefix.clone();
+                cors.new_transform(SharedService(Rc::clone(&service)))
+                    .map_ok(move |middleware| (prefix, middleware))
+            })
+            .collect::<Vec<_>>();
+
+        async move {
+            Ok(ScopedCorsMiddleware {
+                default: default.await?,
+                scoped: future::try_join_all(scoped).await?,
+            })
+        }
+        .boxed_local()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ScopedCorsMiddleware<S> {
+    default: CorsMiddleware<SharedService<S>>,
+    scoped: Vec<(String, CorsMiddleware<SharedService<S>>)>,
+}
+
+impl<S, B> Service<ServiceRequest> for ScopedCorsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(default);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let cors = self
+            .scoped
+            .iter()
+            .find(|(prefix, _)| matches_path_prefix(request.path(), prefix))
+            .map_or(&self.default, |(_, cors)| cors);
+        cors.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct PageQuery {
+        page: u32,
+    }
+
+    async fn query_error_code(uri: &str) -> Option<u8> {
+        let request = TestRequest::get().uri(uri).to_http_request();
+        parse_query_string::<PageQuery>(&request)
+            .await
+            .unwrap_err()
+            .body
+            .error_code
+    }
+
+    async fn body_error_code(body: &'static str) -> Option<u8> {
+        let (request, mut payload) = TestRequest::post()
+            .insert_header(header::ContentType::json())
+            .set_payload(body)
+            .to_http_parts();
+        let payload = Payload::from_request(&request, &mut payload).await.unwrap();
+        extract_query::<PageQuery>(request, payload, EndpointMutability::Mutable, false, false)
+            .await
+            .unwrap_err()
+            .body
+            .error_code
+    }
+
+    #[actix_rt::test]
+    async fn formatted_errors_keep_headers() {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(actix_web::middleware::DefaultHeaders::new().add(("x-version", "1.0")))
+                .wrap(ErrorFormatter::new(
+                    ErrorFormat::JsonApi,
+                    ErrorFieldCase::SnakeCase,
+                ))
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, _>(
+                            ApiError::bad_request().header(header::RETRY_AFTER, "10"),
+                        )
+                    }),
+                ),
+        )
+        .await;
+        let response = actix_web::test::call_service(&app, TestRequest::get().to_request()).await;
+
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(header::CONTENT_TYPE).unwrap(),
+            "application/vnd.api+json"
+        );
+        assert_eq!(headers.get("x-version").unwrap(), "1.0");
+        assert_eq!(headers.get_all(header::RETRY_AFTER).count(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn compressed_raw_body() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"page":2}"#).unwrap();
+        let (request, mut payload) = TestRequest::post()
+            .app_data(RequestConfig::new(
+                None,
+                DEFAULT_JSON_PAYLOAD_SIZE,
+                true,
+                false,
+                QueryFormat::default(),
+            ))
+            .insert_header(header::ContentType::json())
+            .insert_header((header::CONTENT_ENCODING, "gzip"))
+            .set_payload(encoder.finish().unwrap())
+            .to_http_parts();
+        let payload = Payload::from_request(&request, &mut payload).await.unwrap();
+
+        let (query, body) = extract_body::<PageQuery>(&request, payload).await.unwrap();
+        assert_eq!(query.page, 2);
+        assert_eq!(body, Bytes::from_static(br#"{"page":2}"#));
+    }
+
+    #[actix_rt::test]
+    async fn empty_post_body() {
+        let (request, mut payload) = TestRequest::post().to_http_parts();
+        assert!(has_empty_body(&request));
+        let payload = Payload::from_request(&request, &mut payload).await.unwrap();
+        let query = extract_query::<Option<PageQuery>>(
+            request,
+            payload,
+            EndpointMutability::Mutable,
+            false,
+            false,
+        )
+        .await;
+        assert!(query.unwrap().is_none());
+
+        let request = TestRequest::post()
+            .version(actix_web::http::Version::HTTP_2)
+            .to_http_request();
+        assert!(!has_empty_body(&request));
+    }
+
+    #[actix_rt::test]
+    async fn panicking_handler_returns_problem_json() {
+        fn handler(_: PageQuery) -> future::Ready<crate::Result<()>> {
+            panic!("handler failure")
+        }
+
+        let handler = RequestHandler::from(NamedWith::new(
+            "panics",
+            handler,
+            EndpointMutability::Immutable,
+        ));
+        let (request, mut payload) = TestRequest::get().uri("/panics?page=1").to_http_parts();
+        let payload = Payload::from_request(&request, &mut payload).await.unwrap();
+        let error = (handler.inner)(request, payload).await.unwrap_err();
+
+        let response = error.error_response();
+        assert_eq!(response.status(), HttpStatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/problem+json"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn gzip_post_reaches_handler() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        fn handler(query: PageQuery) -> future::Ready<crate::Result<u32>> {
+            future::ok(query.page)
+        }
+
+        let handler = RequestHandler::from(NamedWith::mutable("pages", handler));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"page":2}"#).unwrap();
+        let body = encoder.finish().unwrap();
+        let request = |decompress| {
+            TestRequest::post()
+                .app_data(RequestConfig::new(
+                    None,
+                    DEFAULT_JSON_PAYLOAD_SIZE,
+                    decompress,
+                    false,
+                    QueryFormat::default(),
+                ))
+                .insert_header(header::ContentType::json())
+                .insert_header((header::CONTENT_ENCODING, "gzip"))
+                .set_payload(body.clone())
+                .to_http_parts()
+        };
+
+        let (request_parts, mut payload) = request(true);
+        let payload = Payload::from_request(&request_parts, &mut payload)
+            .await
+            .unwrap();
+        let response = (handler.inner)(request_parts, payload).await.unwrap();
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, Bytes::from_static(b"2"));
+
+        let (request_parts, mut payload) = request(false);
+        let payload = Payload::from_request(&request_parts, &mut payload)
+            .await
+            .unwrap();
+        let error = (handler.inner)(request_parts, payload).await.unwrap_err();
+        assert_eq!(
+            error.error_response().status(),
+            HttpStatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[actix_rt::test]
+    async fn flat_query_errors_have_codes() {
+        assert_eq!(
+            query_error_code("/v1/blocks").await,
+            Some(ParseErrorCode::QueryMissingField as u8)
+        );
+        assert_eq!(
+            query_error_code("/v1/blocks?page=first").await,
+            Some(ParseErrorCode::QueryTypeMismatch as u8)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn body_errors_have_codes() {
+        assert_eq!(
+            body_error_code("{\"page\":").await,
+            Some(ParseErrorCode::Malformed as u8)
+        );
+        assert_eq!(
+            body_error_code("{}").await,
+            Some(ParseErrorCode::MissingField as u8)
+        );
+        assert_eq!(
+            body_error_code("{\"page\":\"first\"}").await,
+            Some(ParseErrorCode::TypeMismatch as u8)
+        );
+    }
+
+    async fn compressed_response(content_type: &'static str) -> ServiceResponse {
+        compressed_response_with(content_type, 4_096, "deflate, gzip;q=0.5").await
+    }
+
+    async fn compressed_response_with(
+        content_type: &'static str,
+        len: usize,
+        accept_encoding: &'static str,
+    ) -> ServiceResponse {
+        let config = CompressionConfig::default();
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(CompressionPolicy::new(config.clone()))
+                .wrap(actix_web::middleware::Compress::default())
+                .wrap(AcceptEncodingFilter::new(&config.encodings))
+                .route(
+                    "/",
+                    web::get().to(move || async move {
+                        HttpResponse::Ok()
+                            .content_type(content_type)
+                            .body(vec![b'a'; len])
+                    }),
+                ),
+        )
+        .await;
+        let request = TestRequest::get()
+            .insert_header((header::ACCEPT_ENCODING, accept_encoding))
+            .to_request();
+        actix_web::test::call_service(&app, request)
+            .await
+            .map_into_boxed_body()
+    }
+
+    #[actix_rt::test]
+    async fn compressible_types_use_allowed_encodings() {
+        let response = compressed_response("application/json").await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn uncompressible_types_pass_through() {
+        for content_type in ["image/png", "application/zip", "video/mp4"] {
+            let response = compressed_response(content_type).await;
+            assert_ne!(
+                response.headers().get(header::CONTENT_ENCODING).unwrap(),
+                "gzip"
+            );
+            let body = actix_web::test::read_body(response).await;
+            assert_eq!(body, vec![b'a'; 4_096]);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn small_bodies_and_disallowed_encodings_pass_through() {
+        let encoding = |response: &ServiceResponse| {
+            response
+                .headers()
+                .get(header::CONTENT_ENCODING)
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+
+        let response = compressed_response_with("application/json", 1_023, "gzip").await;
+        assert_eq!(encoding(&response).as_deref(), Some("identity"));
+        let response = compressed_response_with("application/json", 1_024, "gzip").await;
+        assert_eq!(encoding(&response).as_deref(), Some("gzip"));
+
+        for accept_encoding in ["deflate", "br, deflate;q=0.5"] {
+            let response =
+                compressed_response_with("application/json", 4_096, accept_encoding).await;
+            assert_ne!(encoding(&response).as_deref(), Some("deflate"));
+            let body = actix_web::test::read_body(response).await;
+            assert_eq!(body, vec![b'a'; 4_096]);
+        }
+    }
+
+    #[test]
+    fn accept_encoding_is_filtered() {
+        let filter = |value: &'static str| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            filter_accept_encoding(&headers, &["gzip".to_owned()])
+        };
+        assert_eq!(filter("br, gzip;q=0.5").as_deref(), Some("gzip;q=0.5"));
+        assert_eq!(filter("br;q=1, *;q=0.1").as_deref(), Some("gzip;q=0.1"));
+        assert_eq!(filter("br, identity").as_deref(), Some("identity"));
+        assert_eq!(filter("br"), None);
+    }
+
+    fn wrapped_response<T: Serialize>(value: T) -> HttpResponse {
+        json_response(
+            Actuality::Actual,
+            &DeprecationHeaders::default(),
+            None,
+            None,
+            value,
+        )
+    }
+
+    #[actix_rt::test]
+    async fn response_wrappers_combine() {
+        use crate::{Binary, Either, NoContent, WithHeaders, WithLastModified, WithStatus};
+
+        let date = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let value = WithStatus::new(
+            WithHeaders::new(WithLastModified::new(vec![1, 2], date))
+                .header(header::ETAG, HeaderValue::from_static("\"v1\"")),
+            HttpStatusCode::ACCEPTED,
+        );
+        let response = wrapped_response(value);
+        assert_eq!(response.status(), HttpStatusCode::ACCEPTED);
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), "\"v1\"");
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, Bytes::from_static(b"[1,2]"));
+
+        let value = WithHeaders::new(WithStatus::new(
+            Binary::new("application/x-protobuf", vec![8, 1]),
+            HttpStatusCode::CREATED,
+        ))
+        .header(header::LOCATION, HeaderValue::from_static("/v1/blocks/1"));
+        let response = wrapped_response(value);
+        assert_eq!(response.status(), HttpStatusCode::CREATED);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-protobuf"
+        );
+        assert!(response.headers().contains_key(header::LOCATION));
+
+        let value: Either<NoContent, WithStatus<()>> = Either::Left(NoContent);
+        assert_eq!(wrapped_response(value).status(), HttpStatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn nested_wrappers_are_ignored() {
+        use crate::WithStatus;
+
+        let value = vec![WithStatus::new(1, HttpStatusCode::CREATED)];
+        assert_eq!(wrapped_response(value).status(), HttpStatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn not_modified_keeps_headers() {
+        use crate::WithLastModified;
+
+        let date = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let request = TestRequest::get()
+            .insert_header((header::IF_MODIFIED_SINCE, format_http_date(date)))
+            .to_http_request();
+        let response = json_response(
+            Actuality::Deprecated {
+                discontinued_on: None,
+                description: None,
+                enforce_sunset: false,
+            },
+            &DeprecationHeaders::default(),
+            Some(&CacheControl::max_age(60)),
+            if_modified_since(&request),
+            WithLastModified::new(1, date),
+        );
+        assert_eq!(response.status(), HttpStatusCode::NOT_MODIFIED);
+        let headers = response.headers();
+        assert_eq!(headers.get(header::CACHE_CONTROL).unwrap(), "max-age=60");
+        assert!(headers.contains_key(header::LAST_MODIFIED));
+        assert!(headers.contains_key(header::WARNING));
+
+        let request = TestRequest::post()
+            .insert_header((header::IF_MODIFIED_SINCE, format_http_date(date)))
+            .to_http_request();
+        assert_eq!(if_modified_since(&request), None);
+    }
+
+    #[test]
+    fn http_dates_are_converted_to_gmt() {
+        let date = time::macros::datetime!(2024-01-01 05:30 +05:00);
+        assert_eq!(format_http_date(date), "Mon, 01 Jan 2024 00:30:00 GMT");
+
+        fn handler(_: ()) -> future::Ready<crate::Result<()>> {
+            future::ok(())
+        }
+        let deprecated =
+            crate::Deprecated::<(), (), future::Ready<crate::Result<()>>, _>::new(handler)
+                .with_date(date);
+        let with = crate::With::from(deprecated);
+        let response = json_response(
+            with.actuality,
+            &DeprecationHeaders::new(DeprecationHeader::Both, false, None),
+            None,
+            None,
+            (),
+        );
+        assert_eq!(
+            response.headers().get(SUNSET).unwrap(),
+            "Mon, 01 Jan 2024 00:30:00 GMT"
+        );
+        let warning = response.headers().get(header::WARNING).unwrap();
+        assert!(warning
+            .to_str()
+            .unwrap()
+            .contains("maintained until Mon, 01 Jan 2024 00:30:00 GMT."));
+    }
+
+    fn deprecated_response(deprecation_headers: DeprecationHeaders) -> HttpResponse {
+        json_response(
+            Actuality::Deprecated {
+                discontinued_on: Some(time::macros::datetime!(2024-01-01 00:00 UTC)),
+                description: None,
+                enforce_sunset: false,
+            },
+            &deprecation_headers,
+            None,
+            None,
+            (),
+        )
+    }
+
+    fn header_str(response: &HttpResponse, name: header::HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn deprecation_header_variants() {
+        let response = deprecated_response(DeprecationHeaders::default());
+        let warning = header_str(&response, header::WARNING).unwrap();
+        assert!(warning.starts_with("299 - \"Deprecated API: "));
+        assert_eq!(header_str(&response, SUNSET), None);
+
+        let response = deprecated_response(DeprecationHeaders::new(
+            DeprecationHeader::Warning,
+            false,
+            Some("nanobloc".to_owned()),
+        ));
+        let warning = header_str(&response, header::WARNING).unwrap();
+        assert!(warning.starts_with("299 nanobloc \"Deprecated API: "));
+
+        let response = deprecated_response(DeprecationHeaders::new(
+            DeprecationHeader::Sunset,
+            false,
+            None,
+        ));
+        assert_eq!(header_str(&response, header::WARNING), None);
+        assert_eq!(
+            header_str(&response, SUNSET),
+            Some("Mon, 01 Jan 2024 00:00:00 GMT")
+        );
+
+        let response = deprecated_response(DeprecationHeaders::new(
+            DeprecationHeader::Both,
+            false,
+            None,
+        ));
+        assert!(header_str(&response, header::WARNING).is_some());
+        assert!(header_str(&response, SUNSET).is_some());
+    }
+
+    #[test]
+    fn deprecation_headers_are_consistent() {
+        let response =
+            deprecated_response(DeprecationHeaders::new(DeprecationHeader::Both, true, None));
+        let sunset = header_str(&response, SUNSET).unwrap();
+        let warning = header_str(&response, header::WARNING).unwrap();
+        assert_eq!(sunset, "Mon, 01 Jan 2024 00:00:00 GMT");
+        assert!(warning.contains(&format!("maintained until {}.", sunset)));
+        assert_eq!(header_str(&response, DEPRECATION), Some("true"));
+    }
+
+    #[test]
+    fn deprecation_warning_text_includes_date_and_description() {
+        let date = time::macros::datetime!(2024-03-01 12:00 UTC);
+        assert_eq!(
+            expiration_note(Some(date)),
+            "The old API is maintained until Fri, 01 Mar 2024 12:00:00 GMT."
+        );
+        assert_eq!(
+            expiration_note(None),
+            "Currently there is no specific date for disabling this endpoint."
+        );
+
+        assert_eq!(
+            deprecation_warning_text(Some(date), Some("Use v2/blocks")),
+            "Deprecated API: This endpoint is deprecated, \
+             see the service documentation to find an alternative. \
+             The old API is maintained until Fri, 01 Mar 2024 12:00:00 GMT. \
+             Additional information: Use v2/blocks."
+        );
+        assert!(deprecation_warning_text(None, None)
+            .ends_with("Currently there is no specific date for disabling this endpoint."));
+    }
+
+    #[actix_rt::test]
+    async fn empty_error_bodies_are_rewritten() {
+        use actix_web::{
+            body::BoxBody,
+            test::{call_service, init_service, read_body},
+        };
+
+        async fn error_response_parts(
+            raw_statuses: &[u16],
+            uri: &str,
+        ) -> (HttpStatusCode, Option<String>, Bytes) {
+            let app = init_service(
+                actix_web::App::new()
+                    .wrap(error_handlers::<BoxBody>(raw_statuses))
+                    .route(
+                        "/legacy",
+                        web::get().to(|| async { HttpResponse::NotFound().body("legacy") }),
+                    )
+                    .route(
+                        "/empty",
+                        web::get().to(|| async { HttpResponse::NotFound().finish() }),
+                    ),
+            )
+            .await;
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            let content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .map(|value| value.to_str().unwrap().to_owned());
+            (response.status(), content_type, read_body(response).await)
+        }
+
+        let problem_json = Some("application/problem+json".to_owned());
+        // A body set by the handler is kept.
+        let (status, _, body) = error_response_parts(&[], "/legacy").await;
+        assert_eq!(status, HttpStatusCode::NOT_FOUND);
+        assert_eq!(body, Bytes::from_static(b"legacy"));
+        // Empty bodies are rewritten, whether the handler or the router produced them.
+        for uri in ["/empty", "/missing"] {
+            let (status, content_type, body) = error_response_parts(&[], uri).await;
+            assert_eq!(status, HttpStatusCode::NOT_FOUND);
+            assert_eq!(content_type, problem_json);
+            assert!(!body.is_empty());
+        }
+        // Unless the status is opted out.
+        for uri in ["/empty", "/missing"] {
+            let (status, content_type, body) = error_response_parts(&[404], uri).await;
+            assert_eq!(status, HttpStatusCode::NOT_FOUND);
+            assert_ne!(content_type, problem_json);
+            assert!(body.is_empty());
+        }
+    }
+
+    #[test]
+    fn repeated_keys_are_indexed() {
+        assert_eq!(
+            index_repeated_keys("id=1&page=2&id=3&tags[0]=a&tags[0]=b"),
+            "id[0]=1&page=2&id[1]=3&tags[0]=a&tags[0]=b"
+        );
+        assert_eq!(index_repeated_keys("flag&&flag"), "flag[0]=&flag[1]=");
+    }
+
+    #[test]
+    fn merging_duplicates_is_an_error() {
+        fn handler(_: ()) -> future::Ready<crate::Result<()>> {
+            future::ok(())
+        }
+
+        let mut scope = ApiScope::new();
+        scope.endpoint("v1/blocks", handler);
+        let mut other = ApiScope::new();
+        other.endpoint_mut("v1/blocks", handler);
+        scope.merge(other).unwrap();
+
+        let mut other = ApiScope::new();
+        other
+            .endpoint("v1/status", handler)
+            .endpoint("v1/blocks", handler);
+        assert_eq!(
+            scope.merge(other).unwrap_err(),
+            MergeError::DuplicateEndpoint {
+                name: "v1/blocks".to_owned(),
+                method: "GET".to_owned(),
+            }
+        );
+        assert_eq!(scope.actix_backend.handlers().count(), 2);
+
+        let fallback = |_, _| future::ok(HttpResponse::NotFound().finish());
+        let mut other = ApiScope::new();
+        other.fallback(fallback);
+        scope.merge(other).unwrap();
+        let mut other = ApiScope::new();
+        other.fallback(fallback);
+        assert_eq!(
+            scope.merge(other).unwrap_err(),
+            MergeError::DuplicateFallback
+        );
+    }
+
+    #[test]
+    fn empty_cache_control_is_not_sent() {
+        let response = json_response(
+            Actuality::Actual,
+            &DeprecationHeaders::default(),
+            Some(&CacheControl::default()),
+            None,
+            (),
+        );
+        assert!(!response.headers().contains_key(header::CACHE_CONTROL));
+    }
+
+    struct CapturingLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
+
+    /// Starts capturing log messages of all tests and returns the captured ones matching
+    /// `filter`.
+    fn captured_logs(filter: impl Fn(&str) -> bool) -> Vec<String> {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+        let messages = LOGGER.0.lock().unwrap();
+        messages.iter().filter(|m| filter(m)).cloned().collect()
+    }
+
+    #[actix_rt::test]
+    async fn dropped_requests_are_logged_as_disconnects() {
+        fn stalls(_: PageQuery) -> future::Pending<crate::Result<()>> {
+            future::pending()
+        }
+        fn responds(_: PageQuery) -> future::Ready<crate::Result<()>> {
+            future::ok(())
+        }
+
+        captured_logs(|_| false);
+        let logged = |endpoint: &str| {
+            let message = format!("Client disconnected before endpoint `{}`", endpoint);
+            !captured_logs(|m| m.starts_with(&message)).is_empty()
+        };
+
+        for (name, handler) in [
+            (
+                "stalls",
+                RequestHandler::from(NamedWith::immutable("stalls", stalls)),
+            ),
+            (
+                "responds",
+                RequestHandler::from(NamedWith::immutable("responds", responds)),
+            ),
+        ] {
+            let (request, mut payload) = TestRequest::get().uri("/?page=1").to_http_parts();
+            let payload = Payload::from_request(&request, &mut payload).await.unwrap();
+            let mut future = (handler.inner)(request, payload);
+            let _ = futures::poll!(&mut future);
+            drop(future);
+            assert_eq!(logged(name), name == "stalls");
+        }
+    }
+
+    #[test]
+    fn routes_carry_summary_and_description() {
+        fn handler(_: PageQuery) -> future::Ready<crate::Result<()>> {
+            future::ok(())
+        }
+
+        let mut builder = ApiBuilder::new();
+        builder
+            .raw_handler(RequestHandler::from(NamedWith::immutable(
+                "current",
+                crate::With::from(handler)
+                    .with_summary("Lists items")
+                    .with_description("Items are paged."),
+            )))
+            .raw_handler(RequestHandler::from(NamedWith::immutable(
+                "legacy",
+                crate::Deprecated::new(handler)
+                    .with_description("Use `current`")
+                    .with_summary("Lists items (v1)")
+                    .with_endpoint_description("Items are not paged."),
+            )));
+
+        let routes: Vec<_> = builder
+            .routes()
+            .map(|(name, _, summary, description)| (name, summary, description))
+            .collect();
+        assert_eq!(
+            routes,
+            [
+                ("current", "Lists items", "Items are paged."),
+                ("legacy", "Lists items (v1)", "Items are not paged."),
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn version_tag_is_sent_and_prefixes_etags() {
+        use actix_web::test::{call_service, init_service};
+
+        let app = init_service(
+            actix_web::App::new()
+                .wrap(VersionTag::new("42"))
+                .route(
+                    "/strong",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((header::ETAG, "\"abc\""))
+                            .finish()
+                    }),
+                )
+                .route(
+                    "/weak",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((header::ETAG, "W/\"abc\""))
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+
+        for (uri, etag) in [
+            ("/strong", Some("\"42-abc\"")),
+            ("/weak", Some("W/\"42-abc\"")),
+            ("/missing", None),
+        ] {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.headers().get(&API_VERSION).unwrap(), "42");
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::ETAG)
+                    .map(|value| value.to_str().unwrap()),
+                etag
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn maintenance_rejects_all_but_exempt_paths() {
+        use actix_web::test::{call_service, init_service};
+
+        let mode = MaintenanceMode::new(30, vec!["/healthcheck".to_owned()]);
+        let app = init_service(
+            actix_web::App::new()
+                .wrap(MaintenanceGuard::new(mode.clone()))
+                .route("/healthcheck", web::get().to(HttpResponse::Ok))
+                .route("/items", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |uri| TestRequest::get().uri(uri).to_request();
+
+        let response = call_service(&app, get("/items")).await;
+        assert_eq!(response.status(), HttpStatusCode::OK);
+
+        mode.enable();
+        let response = call_service(&app, get("/items")).await;
+        assert_eq!(response.status(), HttpStatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+        let response = call_service(&app, get("/healthcheck")).await;
+        assert_eq!(response.status(), HttpStatusCode::OK);
+
+        mode.disable();
+        let response = call_service(&app, get("/items")).await;
+        assert_eq!(response.status(), HttpStatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn load_shedding_rejects_requests_over_the_limit() {
+        use actix_web::test::{call_service, init_service};
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let app = init_service(
+            actix_web::App::new()
+                .wrap(LoadShedGuard::new(Some(1), Some(5), Arc::clone(&in_flight)))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::get().to_request()).await;
+        assert_eq!(response.status(), HttpStatusCode::OK);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+
+        // Another request is being processed.
+        in_flight.store(1, Ordering::SeqCst);
+        let response = call_service(&app, TestRequest::get().to_request()).await;
+        assert_eq!(response.status(), HttpStatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "5");
+        assert_eq!(in_flight.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn idempotent_posts_are_replayed() {
+        use actix_web::test::{call_service, init_service, read_body};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = |status: HttpStatusCode| {
+            let calls = Arc::clone(&calls);
+            move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                async move { HttpResponse::build(status).body(call.to_string()) }
+            }
+        };
+        let app = init_service(
+            actix_web::App::new()
+                .wrap(IdempotencyGuard::new(Some(Idempotency::in_memory(
+                    std::time::Duration::from_secs(60),
+                ))))
+                .route("/items", web::route().to(counter(HttpStatusCode::OK)))
+                .route(
+                    "/fails",
+                    web::post().to(counter(HttpStatusCode::INTERNAL_SERVER_ERROR)),
+                ),
+        )
+        .await;
+        let call = |request: TestRequest, key: Option<&'static str>| {
+            let request = match key {
+                Some(key) => request.insert_header((IDEMPOTENCY_KEY, key)),
+                None => request,
+            };
+            let response = call_service(&app, request.to_request());
+            async move {
+                let response = response.await;
+                let status = response.status();
+                (status, read_body(response).await)
+            }
+        };
+
+        let post = || TestRequest::post().uri("/items");
+        assert_eq!(call(post(), Some("a")).await.1, "1");
+        assert_eq!(call(post(), Some("a")).await.1, "1");
+        assert_eq!(call(post(), Some("b")).await.1, "2");
+        assert_eq!(call(post(), None).await.1, "3");
+        let put = TestRequest::put().uri("/items");
+        assert_eq!(call(put, Some("a")).await.1, "4");
+
+        // Server errors are not cached, so a retry runs the handler again.
+        let fails = || TestRequest::post().uri("/fails");
+        let (status, body) = call(fails(), Some("c")).await;
+        assert_eq!(
+            (status, body.as_ref()),
+            (HttpStatusCode::INTERNAL_SERVER_ERROR, &b"5"[..])
+        );
+        assert_eq!(call(fails(), Some("c")).await.1, "6");
+    }
+
+    #[actix_rt::test]
+    async fn cors_policy_follows_the_longest_path_prefix() {
+        use actix_web::test::{call_service, init_service};
+
+        let whitelist = |origin: &str| Cors::from(AllowOrigin::Whitelist(vec![origin.to_owned()]));
+        let app = init_service(
+            actix_web::App::new()
+                .wrap(ScopedCors::new(
+                    whitelist("https://default.example"),
+                    vec![
+                        (
+                            "/api/public/".to_owned(),
+                            whitelist("https://public.example"),
+                        ),
+                        (
+                            "/api/public/admin".to_owned(),
+                            whitelist("https://admin.example"),
+                        ),
+                    ],
+                ))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let allowed = |uri: &'static str, origin: &'static str| {
+            let request = TestRequest::get()
+                .uri(uri)
+                .insert_header((header::ORIGIN, origin))
+                .to_request();
+            let response = call_service(&app, request);
+            async move {
+                let response = response.await;
+                response.status().is_success()
+                    && response
+                        .headers()
+                        .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                        .is_some_and(|value| value == origin)
+            }
+        };
+
+        assert!(allowed("/api/public/items", "https://public.example").await);
+        assert!(allowed("/api/public", "https://public.example").await);
+        assert!(!allowed("/api/public/items", "https://default.example").await);
+        assert!(allowed("/api/public/admin/keys", "https://admin.example").await);
+        assert!(!allowed("/api/public/admin/keys", "https://public.example").await);
+        // Prefixes match whole segments, so `/api/publicity` gets the default policy.
+        assert!(allowed("/api/publicity", "https://default.example").await);
+        assert!(!allowed("/api/publicity", "https://public.example").await);
+    }
+
+    #[actix_rt::test]
+    async fn bodies_of_selected_paths_are_logged_redacted() {
+        use actix_web::test::{call_service, init_service, read_body};
+
+        captured_logs(|_| false);
+        let config = BodyLogging {
+            paths: vec!["/login".to_owned(), "/upload".to_owned()],
+            redacted: vec!["/password".to_owned()],
+        };
+        let echo = || web::post().to(|body: Bytes| async move { HttpResponse::Ok().body(body) });
+        let app = init_service(
+            actix_web::App::new()
+                .wrap(BodyLogger::new(config, 64))
+                .route("/login", echo())
+                .route("/upload", echo())
+                .route("/other", echo()),
+        )
+        .await;
+        let post = |uri: &str, body: &str| {
+            let request = TestRequest::post()
+                .uri(uri)
+                .set_payload(body.to_owned())
+                .to_request();
+            let response = call_service(&app, request);
+            async move { read_body(response.await).await }
+        };
+        let logs = |endpoint: &str| {
+            let endpoint = format!("POST {} ", endpoint);
+            captured_logs(|m| m.starts_with(&endpoint))
+        };
+
+        let credentials = r#"{"password":"hunter2","user":"alice"}"#;
+        assert_eq!(post("/login", credentials).await, credentials);
+        let redacted = r#"{"password":"<redacted>","user":"alice"}"#;
+        assert_eq!(
+            logs("/login"),
+            [
+                format!("POST /login request body: {}", redacted),
+                format!("POST /login response body: {}", redacted),
+            ]
+        );
+
+        let large = "a".repeat(65);
+        assert_eq!(post("/upload", &large).await, large);
+        assert_eq!(
+            logs("/upload"),
+            [
+                "POST /upload request body is not logged",
+                "POST /upload response body is not logged",
+            ]
+        );
+
+        assert_eq!(post("/other", credentials).await, credentials);
+        assert!(logs("/other").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn handlers_time_out_at_the_request_deadline() {
+        fn stalls(_: PageQuery) -> future::Pending<crate::Result<()>> {
+            future::pending()
+        }
+
+        let handler = RequestHandler::from(NamedWith::immutable("stalls", stalls));
+        for (name, value) in [("grpc-timeout", "10m"), ("x-deadline", "1000")] {
+            let (request, mut payload) = TestRequest::get()
+                .uri("/?page=1")
+                .insert_header((name, value))
+                .to_http_parts();
+            let payload = Payload::from_request(&request, &mut payload).await.unwrap();
+            let response = match (handler.inner)(request, payload).await {
+                Ok(response) => response,
+                Err(e) => e.error_response(),
+            };
+            assert_eq!(response.status(), HttpStatusCode::GATEWAY_TIMEOUT);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn proto_sources_are_served() {
+        use actix_web::test::{call_service, init_service, read_body};
+
+        let mut builder = ApiBuilder::new();
+        builder.proto_sources(&[
+            ("service.proto", "syntax = \"proto3\";"),
+            ("common/types.proto", "package types;"),
+        ]);
+        let app =
+            init_service(actix_web::App::new().service(builder.wire(web::scope("/service")))).await;
+        let get = |uri: &str| {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request());
+            async move {
+                let response = response.await;
+                let status = response.status();
+                (status, read_body(response).await)
+            }
+        };
+
+        let (status, body) = get("/service/schema").await;
+        assert_eq!(status, HttpStatusCode::OK);
+        assert_eq!(body, r#"["common/types.proto","service.proto"]"#);
+        let (status, body) = get("/service/schema/common/types.proto").await;
+        assert_eq!(status, HttpStatusCode::OK);
+        assert_eq!(body, "package types;");
+        let (status, _) = get("/service/schema/missing.proto").await;
+        assert_eq!(status, HttpStatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn fallback_handles_unmatched_requests_of_its_scope() {
+        use actix_web::test::{call_service, init_service, read_body};
+
+        fn handler(_: PageQuery) -> future::Ready<crate::Result<u32>> {
+            future::ok(1)
+        }
+
+        let mut builder = ApiBuilder::new();
+        builder
+            .raw_handler(RequestHandler::from(NamedWith::immutable("items", handler)))
+            .fallback(|request: HttpRequest, _| {
+                let path = request.path().to_owned();
+                async move { Ok(HttpResponse::ImATeapot().body(path)) }
+            });
+        let app = init_service(
+            actix_web::App::new()
+                .service(builder.wire(web::scope("/service")))
+                .default_service(web::to(HttpResponse::NotFound)),
+        )
+        .await;
+        let get = |uri: &str| {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request());
+            async move {
+                let response = response.await;
+                let status = response.status();
+                (status, read_body(response).await)
+            }
+        };
+
+        let (status, body) = get("/service/items?page=1").await;
+        assert_eq!((status, body.as_ref()), (HttpStatusCode::OK, &b"1"[..]));
+        let (status, body) = get("/service/unknown").await;
+        assert_eq!(
+            (status, body.as_ref()),
+            (HttpStatusCode::IM_A_TEAPOT, &b"/service/unknown"[..])
+        );
+        let (status, _) = get("/other").await;
+        assert_eq!(status, HttpStatusCode::NOT_FOUND);
+    }
 }
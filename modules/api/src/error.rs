@@ -1,11 +1,13 @@
 pub use actix_web::http::{
-    header::{self, HeaderMap, HeaderName},
+    header::{self, HeaderMap, HeaderName, HeaderValue},
     StatusCode as HttpStatusCode,
 };
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use thiserror::Error;
 
+use crate::RequestContext;
+
 #[derive(Error, Debug)]
 #[non_exhaustive]
 pub struct Error {
@@ -24,7 +26,89 @@ impl Default for Error {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorFormat {
+    #[default]
+    ProblemJson,
+    JsonApi,
+}
+
+/// Casing of multi-word `ErrorBody` fields on the wire, e.g. `error_code` or `errorCode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorFieldCase {
+    #[default]
+    SnakeCase,
+    CamelCase,
+}
+
+/// `error_code` of `400 Bad Request` responses to requests whose query or body cannot
+/// be deserialized, derived from the serde error category. Query string errors have
+/// their own `Query*` codes, so that e.g. a missing field is `2` in a JSON body but `12`
+/// in the query string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[non_exhaustive]
+pub enum ParseErrorCode {
+    /// The body is not syntactically valid JSON.
+    Malformed = 1,
+    MissingField = 2,
+    /// A value has a wrong type, is out of range or is not a known variant.
+    TypeMismatch = 3,
+    UnknownField = 4,
+    QueryMalformed = 11,
+    QueryMissingField = 12,
+    QueryTypeMismatch = 13,
+    QueryUnknownField = 14,
+}
+
+impl ParseErrorCode {
+    /// Returns the code of the same error in the query string.
+    pub fn in_query(self) -> Self {
+        match self {
+            ParseErrorCode::Malformed => ParseErrorCode::QueryMalformed,
+            ParseErrorCode::MissingField => ParseErrorCode::QueryMissingField,
+            ParseErrorCode::TypeMismatch => ParseErrorCode::QueryTypeMismatch,
+            ParseErrorCode::UnknownField => ParseErrorCode::QueryUnknownField,
+            code => code,
+        }
+    }
+
+    pub(crate) fn from_message(message: &str) -> Option<Self> {
+        if message.starts_with("missing field") {
+            Some(ParseErrorCode::MissingField)
+        } else if message.starts_with("unknown field") {
+            Some(ParseErrorCode::UnknownField)
+        } else if [
+            "invalid type",
+            "invalid value",
+            "invalid length",
+            "unknown variant",
+        ]
+        .iter()
+        .any(|prefix| message.starts_with(prefix))
+        {
+            Some(ParseErrorCode::TypeMismatch)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn from_json(error: &serde_json::Error) -> Option<Self> {
+        use serde_json::error::Category;
+
+        match error.classify() {
+            Category::Syntax | Category::Eof => Some(ParseErrorCode::Malformed),
+            Category::Data => Self::from_message(&error.to_string()),
+            Category::Io => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[non_exhaustive]
 pub struct ErrorBody {
     #[serde(rename = "type", default, skip_serializing_if = "String::is_empty")]
@@ -35,8 +119,29 @@ pub struct ErrorBody {
     pub detail: String,
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub source: String,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default, alias = "errorCode", skip_serializing_if = "Option::is_none")]
     pub error_code: Option<u8>,
+    /// Correlates the error with server logs; filled from the `X-Request-Id` request
+    /// header unless set by the handler.
+    #[serde(default, alias = "traceId", skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+impl ErrorBody {
+    pub fn to_json(&self, case: ErrorFieldCase) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        if case == ErrorFieldCase::CamelCase {
+            if let Some(object) = value.as_object_mut() {
+                if let Some(code) = object.remove("error_code") {
+                    object.insert("errorCode".to_owned(), code);
+                }
+                if let Some(trace_id) = object.remove("trace_id") {
+                    object.insert("traceId".to_owned(), trace_id);
+                }
+            }
+        }
+        value
+    }
 }
 
 impl fmt::Display for Error {
@@ -96,6 +201,25 @@ impl Error {
         self
     }
 
+    pub fn trace_id(mut self, trace_id: impl Into<String>) -> Self {
+        self.body.trace_id = Some(trace_id.into());
+        self
+    }
+
+    pub(crate) fn parse_error_code(self, code: Option<ParseErrorCode>) -> Self {
+        match code {
+            Some(code) => self.error_code(code as u8),
+            None => self,
+        }
+    }
+
+    pub(crate) fn query_parse_error_code(self, code: Option<ParseErrorCode>) -> Self {
+        match code {
+            Some(code) => self.error_code(code.in_query() as u8),
+            None => self,
+        }
+    }
+
     pub(crate) fn header(mut self, key: HeaderName, value: &str) -> Self {
         self.headers.insert(key, value.parse().unwrap());
         self
@@ -119,10 +243,19 @@ impl Error {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RedirectBase {
+    Verbatim,
+    Relative,
+    Absolute(String),
+}
+
+/// Redirect error; by default the location is emitted in the `Location` header verbatim.
 #[derive(Debug)]
 pub struct MovedPermanentlyError {
     location: String,
     query_part: Option<String>,
+    base: RedirectBase,
 }
 
 impl MovedPermanentlyError {
@@ -130,6 +263,7 @@ impl MovedPermanentlyError {
         Self {
             location,
             query_part: None,
+            base: RedirectBase::Verbatim,
         }
     }
     pub fn with_query<Q: Serialize>(self, query: Q) -> Self {
@@ -140,15 +274,85 @@ impl MovedPermanentlyError {
             ..self
         }
     }
+
+    /// Drops the scheme and host from the location, emitting only its path.
+    pub fn relative(self) -> Self {
+        Self {
+            base: RedirectBase::Relative,
+            ..self
+        }
+    }
+
+    /// Emits the location path resolved against `base`, e.g. `https://example.com/`.
+    pub fn with_base<S: Into<String>>(self, base: S) -> Self {
+        Self {
+            base: RedirectBase::Absolute(base.into()),
+            ..self
+        }
+    }
+
+    /// Resolves the location against the host the client used to reach the server,
+    /// falling back to a relative location if it cannot be determined.
+    pub fn with_forwarded_base(self, context: &RequestContext) -> Self {
+        match context.forwarded_base() {
+            Some(base) => self.with_base(base),
+            None => self.relative(),
+        }
+    }
+}
+
+fn relative_location(location: &str) -> &str {
+    match location.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |pos| &rest[pos..]),
+        None => location,
+    }
 }
 
 impl From<MovedPermanentlyError> for Error {
     fn from(e: MovedPermanentlyError) -> Self {
+        let location = match e.base {
+            RedirectBase::Verbatim => e.location,
+            RedirectBase::Relative => relative_location(&e.location).to_owned(),
+            RedirectBase::Absolute(base) => {
+                let path = relative_location(&e.location);
+                format!(
+                    "{}/{}",
+                    base.trim_end_matches('/'),
+                    path.trim_start_matches('/')
+                )
+            }
+        };
         let full_location = match e.query_part {
-            Some(query) => format!("{}?{}", e.location, query),
-            None => e.location,
+            Some(query) => format!("{}?{}", location, query),
+            None => location,
         };
 
         Error::new(HttpStatusCode::MOVED_PERMANENTLY).header(header::LOCATION, &full_location)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_body_round_trips_in_both_cases() {
+        let body = ErrorBody {
+            title: "Invalid query".to_owned(),
+            error_code: Some(12),
+            trace_id: Some("abc".to_owned()),
+            ..ErrorBody::default()
+        };
+        for (case, code_field) in [
+            (ErrorFieldCase::SnakeCase, "error_code"),
+            (ErrorFieldCase::CamelCase, "errorCode"),
+        ] {
+            let json = body.to_json(case);
+            assert_eq!(json[code_field], 12);
+            let parsed: ErrorBody = serde_json::from_value(json).unwrap();
+            assert_eq!(parsed.title, body.title);
+            assert_eq!(parsed.error_code, Some(12));
+            assert_eq!(parsed.trace_id.as_deref(), Some("abc"));
+        }
+    }
+}
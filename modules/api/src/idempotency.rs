@@ -0,0 +1,247 @@
+use actix_web::{web::Bytes, HttpResponse};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::BuildHasher,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{HeaderMap, HeaderName},
+    Error, HttpStatusCode,
+};
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: HttpStatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    pub(crate) fn to_response(&self) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.status);
+        for (name, value) in &self.headers {
+            builder.append_header((name.clone(), value.clone()));
+        }
+        builder.body(self.body.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum IdempotencyState {
+    /// The key has not been seen; the caller must eventually `complete` or `abort` it.
+    New,
+    InFlight,
+    Completed(CachedResponse),
+}
+
+pub trait IdempotencyStore: fmt::Debug + Send + Sync {
+    /// Stores should expire `InFlight` keys too, in case `complete` or `abort` is never
+    /// called (e.g. the process crashes while handling the request).
+    fn begin(&self, key: &str) -> IdempotencyState;
+
+    fn complete(&self, key: &str, response: CachedResponse);
+
+    fn abort(&self, key: &str);
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    response: Option<CachedResponse>,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+struct MemoryEntries {
+    entries: HashMap<String, MemoryEntry>,
+    next_purge: Instant,
+}
+
+/// Keeps responses in memory for `ttl`; keys of requests still in flight expire after
+/// `ttl` as well.
+#[derive(Debug)]
+pub struct MemoryIdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<MemoryEntries>,
+}
+
+impl MemoryIdempotencyStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(MemoryEntries {
+                entries: HashMap::new(),
+                next_purge: Instant::now() + ttl,
+            }),
+        }
+    }
+}
+
+impl IdempotencyStore for MemoryIdempotencyStore {
+    fn begin(&self, key: &str) -> IdempotencyState {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        // Expired entries are dropped at most once per TTL rather than on every request.
+        if entries.next_purge <= now {
+            entries.entries.retain(|_, entry| entry.expires_at > now);
+            entries.next_purge = now + self.ttl;
+        }
+
+        match entries.entries.get(key) {
+            Some(entry) if entry.expires_at > now => match entry.response {
+                Some(ref response) => IdempotencyState::Completed(response.clone()),
+                None => IdempotencyState::InFlight,
+            },
+            _ => {
+                let entry = MemoryEntry {
+                    response: None,
+                    expires_at: now + self.ttl,
+                };
+                entries.entries.insert(key.to_owned(), entry);
+                IdempotencyState::New
+            }
+        }
+    }
+
+    fn complete(&self, key: &str, response: CachedResponse) {
+        let entry = MemoryEntry {
+            response: Some(response),
+            expires_at: Instant::now() + self.ttl,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.entries.insert(key.to_owned(), entry);
+    }
+
+    fn abort(&self, key: &str) {
+        self.entries.lock().unwrap().entries.remove(key);
+    }
+}
+
+/// Key passed to `IdempotencyStore::begin` that aborts it when dropped before completion,
+/// e.g. when the client disconnects and the handler future is cancelled.
+#[derive(Debug)]
+pub(crate) struct PendingKey {
+    idempotency: Idempotency,
+    key: Option<String>,
+}
+
+impl PendingKey {
+    pub(crate) fn complete(mut self, response: CachedResponse) {
+        if let Some(key) = self.key.take() {
+            self.idempotency.store.complete(&key, response);
+        }
+    }
+}
+
+impl Drop for PendingKey {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.idempotency.store.abort(&key);
+        }
+    }
+}
+
+/// Replays responses of mutable endpoints for requests repeating an `Idempotency-Key`.
+///
+/// Keys are scoped by the client credentials (the `Authorization` header or the TLS
+/// client certificate), or by the client address for anonymous requests.
+#[derive(Debug, Clone)]
+pub struct Idempotency {
+    store: Arc<dyn IdempotencyStore>,
+    hasher: RandomState,
+}
+
+impl Idempotency {
+    pub fn new<S: IdempotencyStore + 'static>(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn in_memory(ttl: Duration) -> Self {
+        Self::new(MemoryIdempotencyStore::new(ttl))
+    }
+
+    /// Starts handling of the request with `key` sent by `client` to `path`.
+    pub(crate) fn begin(
+        &self,
+        client: &[u8],
+        path: &str,
+        key: &[u8],
+    ) -> Result<PendingKey, IdempotencyState> {
+        // Credentials are hashed so that they are not kept in the store.
+        let key = format!(
+            "{:016x} {} {}",
+            self.hasher.hash_one(client),
+            path,
+            String::from_utf8_lossy(key)
+        );
+        match self.store.begin(&key) {
+            IdempotencyState::New => Ok(PendingKey {
+                idempotency: self.clone(),
+                key: Some(key),
+            }),
+            state => Err(state),
+        }
+    }
+
+    pub(crate) fn conflict_error(&self) -> Error {
+        Error::new(HttpStatusCode::CONFLICT)
+            .title("Request in progress")
+            .detail("A request with the same idempotency key is still being processed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> CachedResponse {
+        CachedResponse {
+            status: HttpStatusCode::CREATED,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"{}"),
+        }
+    }
+
+    #[test]
+    fn dropped_key_is_released() {
+        let idempotency = Idempotency::in_memory(Duration::from_secs(60));
+        let pending = idempotency.begin(b"client", "/v1/tx", b"key").unwrap();
+        assert!(matches!(
+            idempotency.begin(b"client", "/v1/tx", b"key"),
+            Err(IdempotencyState::InFlight)
+        ));
+
+        drop(pending);
+        let pending = idempotency.begin(b"client", "/v1/tx", b"key").unwrap();
+        pending.complete(response());
+        assert!(matches!(
+            idempotency.begin(b"client", "/v1/tx", b"key"),
+            Err(IdempotencyState::Completed(cached)) if cached.status == HttpStatusCode::CREATED
+        ));
+    }
+
+    #[test]
+    fn keys_are_scoped_by_client() {
+        let idempotency = Idempotency::in_memory(Duration::from_secs(60));
+        let pending = idempotency.begin(b"alice", "/v1/tx", b"key").unwrap();
+        pending.complete(response());
+        assert!(idempotency.begin(b"bob", "/v1/tx", b"key").is_ok());
+    }
+
+    #[test]
+    fn in_flight_keys_expire() {
+        let store = MemoryIdempotencyStore::new(Duration::from_millis(10));
+        assert!(matches!(store.begin("key"), IdempotencyState::New));
+        assert!(matches!(store.begin("key"), IdempotencyState::InFlight));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(matches!(store.begin("key"), IdempotencyState::New));
+    }
+}
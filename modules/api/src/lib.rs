@@ -1,17 +1,53 @@
+#[cfg(feature = "tls")]
+pub use self::tls::{ClientIdentity, TlsConfig};
+#[cfg(feature = "watch")]
+pub use self::watch::{watch_endpoints, EndpointsWatcher};
 pub use self::{
-    cors::AllowOrigin,
-    error::{Error, ErrorBody, HttpStatusCode, MovedPermanentlyError},
-    manager::{ApiManager, ApiManagerConfig, UpdateEndpoints, WebServerConfig},
-    withs::{Actuality, Deprecated, NamedWith, Result, With},
+    auth::ApiKeyAuth,
+    breaker::{CircuitBreaker, CircuitState},
+    context::{resolve_client_ip, ForwardedHeader, RequestContext, DEADLINE, GRPC_TIMEOUT},
+    cors::{AllowOrigin, ParseAllowOriginError},
+    end::actix::MergeError,
+    error::{
+        Error, ErrorBody, ErrorFieldCase, ErrorFormat, HttpStatusCode, MovedPermanentlyError,
+        ParseErrorCode,
+    },
+    idempotency::{
+        CachedResponse, Idempotency, IdempotencyState, IdempotencyStore, MemoryIdempotencyStore,
+        IDEMPOTENCY_KEY,
+    },
+    maintenance::MaintenanceMode,
+    manager::{
+        ApiManager, ApiManagerConfig, ApiManagerConfigBuilder, AppConfigurator, CompressionConfig,
+        DeprecationHeader, ManagerEvent, PathNormalization, QueryFormat, SecurityHeaders,
+        SharedData, StaticFileConfig, UpdateEndpoints, WebServerConfig, WellKnownFiles,
+    },
+    withs::{
+        Actuality, Binary, CacheControl, CacheVisibility, Deprecated, Either, NamedWith, NoContent,
+        Result, With, WithHeaders, WithLastModified, WithStatus,
+    },
 };
 
+mod auth;
+mod breaker;
+mod context;
 mod cors;
 mod end;
 mod error;
+mod idempotency;
+mod maintenance;
 mod manager;
+mod response_meta;
+#[cfg(debug_assertions)]
+mod schema;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "watch")]
+mod watch;
 mod withs;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{collections::BTreeMap, fmt, future::Future};
+use time::OffsetDateTime;
 
 use crate::end::actix;
 
@@ -35,6 +71,9 @@ pub trait ApiBackend: Sized {
         Self::Handler: From<NamedWith<Q, I, R, F>>,
     {
         let named_with = NamedWith::immutable(name, endpoint);
+        if !named_with.inner.enabled {
+            return self;
+        }
         self.raw_handler(Self::Handler::from(named_with))
     }
 
@@ -47,6 +86,9 @@ pub trait ApiBackend: Sized {
         Self::Handler: From<NamedWith<Q, I, R, F>>,
     {
         let named_with = NamedWith::mutable(name, endpoint);
+        if !named_with.inner.enabled {
+            return self;
+        }
         self.raw_handler(Self::Handler::from(named_with))
     }
 
@@ -89,6 +131,108 @@ impl ApiScope {
         self
     }
 
+    /// Adds the endpoint only if `enabled`, e.g. to expose it in some environments only.
+    pub fn endpoint_if<Q, I, R, F, E>(
+        &mut self,
+        enabled: bool,
+        name: &str,
+        endpoint: E,
+    ) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        I: Serialize + 'static,
+        F: Fn(Q) -> R + 'static + Clone + Send + Sync,
+        E: Into<With<Q, I, R, F>>,
+        R: Future<Output = crate::Result<I>>,
+    {
+        let endpoint: With<Q, I, R, F> = endpoint.into();
+        self.endpoint(name, endpoint.with_enabled(enabled))
+    }
+
+    pub fn endpoint_mut_if<Q, I, R, F, E>(
+        &mut self,
+        enabled: bool,
+        name: &str,
+        endpoint: E,
+    ) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        I: Serialize + 'static,
+        F: Fn(Q) -> R + 'static + Clone + Send + Sync,
+        E: Into<With<Q, I, R, F>>,
+        R: Future<Output = crate::Result<I>>,
+    {
+        let endpoint: With<Q, I, R, F> = endpoint.into();
+        self.endpoint_mut(name, endpoint.with_enabled(enabled))
+    }
+
+    pub fn endpoint_raw<Q, R, F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        F: Fn(Q) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = crate::Result<actix::HttpResponse>> + 'static,
+    {
+        self.actix_backend
+            .raw_endpoint(name, EndpointMutability::Immutable, handler);
+        self
+    }
+
+    pub fn endpoint_mut_raw<Q, R, F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        F: Fn(Q) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = crate::Result<actix::HttpResponse>> + 'static,
+    {
+        self.actix_backend
+            .raw_endpoint(name, EndpointMutability::Mutable, handler);
+        self
+    }
+
+    pub fn endpoint_stream<Q, I, R, F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        I: Serialize + 'static,
+        F: Fn(Q, actix::BodyStream) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = crate::Result<I>> + 'static,
+    {
+        self.actix_backend.streaming_endpoint(name, handler);
+        self
+    }
+
+    /// Adds a mutable endpoint whose handler also gets the exact body bytes received,
+    /// e.g. to verify a signature over them.
+    pub fn endpoint_mut_with_body<Q, I, R, F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        I: Serialize + 'static,
+        F: Fn(Q, actix::Bytes) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = crate::Result<I>> + 'static,
+    {
+        self.actix_backend.raw_body_endpoint(name, handler);
+        self
+    }
+
+    pub fn proto_sources(&mut self, sources: &[(&str, &str)]) -> &mut Self {
+        self.actix_backend.proto_sources(sources);
+        self
+    }
+
+    pub fn fallback<F, R>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(actix::HttpRequest, actix::Payload) -> R + 'static + Clone + Send + Sync,
+        R: Future<Output = crate::Result<actix::HttpResponse>> + 'static,
+    {
+        self.actix_backend.fallback(handler);
+        self
+    }
+
+    /// Adds the endpoints and fallback of `other`; fails without changing this scope if
+    /// both define the same endpoint or a fallback.
+    pub fn merge(&mut self, other: ApiScope) -> std::result::Result<&mut Self, MergeError> {
+        self.actix_backend.merge(other.actix_backend)?;
+        Ok(self)
+    }
+
     pub fn web_backend(&mut self) -> &mut actix::ApiBuilder {
         &mut self.actix_backend
     }
@@ -98,6 +242,7 @@ impl ApiScope {
 pub struct ApiBuilder {
     pub public_scope: ApiScope,
     pub private_scope: ApiScope,
+    pub internal_scope: ApiScope,
 }
 
 impl ApiBuilder {
@@ -112,13 +257,90 @@ impl ApiBuilder {
     pub fn private_scope(&mut self) -> &mut ApiScope {
         &mut self.private_scope
     }
+
+    pub fn internal_scope(&mut self) -> &mut ApiScope {
+        &mut self.internal_scope
+    }
+
+    /// Adds an immutable endpoint to both the public and private scopes. The handler is
+    /// built once and shared by both scopes, along with any state it holds, such as its
+    /// circuit breaker.
+    pub fn shared_endpoint<Q, I, R, F, E>(&mut self, name: &str, endpoint: E) -> &mut Self
+    where
+        Q: DeserializeOwned + 'static,
+        I: Serialize + 'static,
+        F: Fn(Q) -> R + 'static + Clone + Send + Sync,
+        E: Into<With<Q, I, R, F>>,
+        R: Future<Output = crate::Result<I>>,
+    {
+        let named_with = NamedWith::immutable(name, endpoint);
+        if !named_with.inner.enabled {
+            return self;
+        }
+        let handler = actix::RequestHandler::from(named_with);
+        self.public_scope.web_backend().raw_handler(handler.clone());
+        self.private_scope.web_backend().raw_handler(handler);
+        self
+    }
+
+    pub fn from_scopes(public_scope: ApiScope, private_scope: ApiScope) -> Self {
+        Self {
+            public_scope,
+            private_scope,
+            internal_scope: ApiScope::default(),
+        }
+    }
+
+    fn scope_mut(&mut self, access: ApiAccess) -> &mut ApiScope {
+        match access {
+            ApiAccess::Public => &mut self.public_scope,
+            ApiAccess::Private => &mut self.private_scope,
+            ApiAccess::Internal => &mut self.internal_scope,
+        }
+    }
+
+    fn scope(&self, access: ApiAccess) -> &ApiScope {
+        match access {
+            ApiAccess::Public => &self.public_scope,
+            ApiAccess::Private => &self.private_scope,
+            ApiAccess::Internal => &self.internal_scope,
+        }
+    }
+}
+
+/// Adds handlers to the scopes of their access level, e.g. a route table built from data
+/// with handlers converted from `NamedWith` by `into()`.
+impl Extend<(ApiAccess, actix::RequestHandler)> for ApiBuilder {
+    fn extend<T>(&mut self, handlers: T)
+    where
+        T: IntoIterator<Item = (ApiAccess, actix::RequestHandler)>,
+    {
+        for (access, handler) in handlers {
+            self.scope_mut(access).web_backend().raw_handler(handler);
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+impl FromIterator<(ApiAccess, actix::RequestHandler)> for ApiBuilder {
+    fn from_iter<T>(handlers: T) -> Self
+    where
+        T: IntoIterator<Item = (ApiAccess, actix::RequestHandler)>,
+    {
+        let mut builder = Self::new();
+        builder.extend(handlers);
+        builder
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[non_exhaustive]
 pub enum ApiAccess {
     Public,
     Private,
+    /// Cluster-internal endpoints (metrics, debug) that are served only by their own server
+    /// and never exposed through the public or private ones.
+    Internal,
 }
 
 impl fmt::Display for ApiAccess {
@@ -126,10 +348,21 @@ impl fmt::Display for ApiAccess {
         match *self {
             ApiAccess::Public => f.write_str("public"),
             ApiAccess::Private => f.write_str("private"),
+            ApiAccess::Internal => f.write_str("internal"),
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeprecationInfo {
+    pub access: ApiAccess,
+    pub scope: String,
+    pub name: String,
+    pub method: String,
+    pub discontinued_on: Option<OffsetDateTime>,
+    pub description: Option<String>,
+}
+
 pub trait ExtendApiBackend {
     fn extend<'a, I>(self, items: I) -> Self
     where
@@ -150,18 +383,76 @@ impl ApiAggregator {
         self.endpoints.insert(name.to_owned(), api);
     }
 
+    /// Serves `api` under `name` and every alias; the handlers are shared, not rebuilt.
+    pub fn insert_with_aliases(&mut self, name: &str, aliases: &[&str], api: ApiBuilder) {
+        for alias in aliases {
+            self.endpoints.insert((*alias).to_owned(), api.clone());
+        }
+        self.endpoints.insert(name.to_owned(), api);
+    }
+
     pub fn extend(&mut self, endpoints: impl IntoIterator<Item = (String, ApiBuilder)>) {
         self.endpoints.extend(endpoints);
     }
 
+    /// Adds the services of `other`, e.g. contributed by another subsystem. Like `insert`,
+    /// a service of `other` replaces a service of `self` with the same name.
+    pub fn merge(&mut self, other: ApiAggregator) {
+        self.endpoints.extend(other.endpoints);
+    }
+
+    /// Lists the scope, name, method, summary and description of the endpoints with
+    /// the given access level.
+    pub fn routes(
+        &self,
+        access: ApiAccess,
+    ) -> impl Iterator<Item = (&str, &str, &actix::HttpMethod, &str, &str)> {
+        self.endpoints.iter().flat_map(move |(scope, builder)| {
+            builder.scope(access).actix_backend.routes().map(
+                move |(name, method, summary, description)| {
+                    (scope.as_str(), name, method, summary, description)
+                },
+            )
+        })
+    }
+
+    /// Lists deprecated endpoints of all access levels.
+    pub fn deprecated_endpoints(&self) -> Vec<DeprecationInfo> {
+        let accesses = [ApiAccess::Public, ApiAccess::Private, ApiAccess::Internal];
+        accesses
+            .iter()
+            .flat_map(|&access| {
+                self.endpoints.iter().flat_map(move |(scope, builder)| {
+                    builder
+                        .scope(access)
+                        .actix_backend
+                        .handlers()
+                        .filter_map(move |handler| match handler.actuality {
+                            Actuality::Deprecated {
+                                discontinued_on,
+                                ref description,
+                                ..
+                            } => Some(DeprecationInfo {
+                                access,
+                                scope: scope.clone(),
+                                name: handler.name.clone(),
+                                method: handler.method.to_string(),
+                                discontinued_on,
+                                description: description.clone(),
+                            }),
+                            Actuality::Actual => None,
+                        })
+                })
+            })
+            .collect()
+    }
+
     #[doc(hidden)]
     pub fn extend_backend<B: ExtendApiBackend>(&self, access: ApiAccess, backend: B) -> B {
-        let endpoints = self.endpoints.iter();
-        match access {
-            ApiAccess::Public => backend
-                .extend(endpoints.map(|(name, builder)| (name.as_str(), &builder.public_scope))),
-            ApiAccess::Private => backend
-                .extend(endpoints.map(|(name, builder)| (name.as_str(), &builder.private_scope))),
-        }
+        backend.extend(
+            self.endpoints
+                .iter()
+                .map(|(name, builder)| (name.as_str(), builder.scope(access))),
+        )
     }
 }
@@ -0,0 +1,53 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::{error::header, Error, HttpStatusCode};
+
+#[derive(Debug)]
+struct MaintenanceState {
+    enabled: AtomicBool,
+    retry_after: u64,
+    exempt_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceMode {
+    inner: Arc<MaintenanceState>,
+}
+
+impl MaintenanceMode {
+    pub fn new(retry_after: u64, exempt_paths: Vec<String>) -> Self {
+        Self {
+            inner: Arc::new(MaintenanceState {
+                enabled: AtomicBool::new(false),
+                retry_after,
+                exempt_paths,
+            }),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.inner.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn disable(&self) {
+        self.inner.enabled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn rejects(&self, path: &str) -> bool {
+        self.is_enabled() && !self.inner.exempt_paths.iter().any(|p| p == path)
+    }
+
+    pub(crate) fn error(&self) -> Error {
+        Error::new(HttpStatusCode::SERVICE_UNAVAILABLE)
+            .title("Service is under maintenance")
+            .detail("The service is temporarily unavailable due to maintenance")
+            .header(header::RETRY_AFTER, &self.inner.retry_after.to_string())
+    }
+}
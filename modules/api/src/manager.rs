@@ -1,30 +1,311 @@
 use actix_cors::Cors;
 use actix_web::{
-    web::{self, JsonConfig},
-    App, HttpServer,
+    http::header,
+    middleware::{Compress, Condition, DefaultHeaders, NormalizePath, TrailingSlash},
+    web::{self, Bytes, Data, JsonConfig, ServiceConfig},
+    App, HttpResponse, HttpServer,
 };
 use futures::{
-    channel::mpsc,
-    future::{join_all, try_join_all},
+    channel::{mpsc, oneshot},
+    future::{join_all, try_join_all, BoxFuture, Shared},
     prelude::*,
+    stream::FusedStream,
 };
-use tokio::time::sleep;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{runtime::Handle, time::sleep};
 
 use std::{
-    collections::HashMap,
-    io,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs, io,
     net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{atomic::AtomicUsize, Arc, Mutex},
     time::Duration,
 };
 
-use crate::{end::actix::error_handlers, AllowOrigin, ApiAccess, ApiAggregator, ApiBuilder};
+use crate::{
+    context::{DefaultTimeout, ForwardedHeader, PublicBaseUrl, TrustedProxies},
+    end::actix::{
+        error_handlers, AcceptEncodingFilter, ApiKeyGuard, BodyLogger, CompressionPolicy,
+        DeprecationHeaders, ErrorFormatter, IdempotencyGuard, LoadShedGuard, MaintenanceGuard,
+        RequestConfig, ScopedCors, VersionTag, API_VERSION,
+    },
+    AllowOrigin, ApiAccess, ApiAggregator, ApiBuilder, ApiKeyAuth, ErrorFieldCase, ErrorFormat,
+    Idempotency, MaintenanceMode,
+};
 
-#[derive(Debug, Clone)]
+/// JSON payload limit used unless `json_payload_size` is set; the same as the default
+/// limit of `actix_web::web::JsonConfig`.
+pub(crate) const DEFAULT_JSON_PAYLOAD_SIZE: usize = 2_097_152;
+/// Backlog used by `HttpServer` unless configured otherwise.
+const DEFAULT_BACKLOG: u32 = 2048;
+
+/// How query strings are deserialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum QueryFormat {
+    /// Flat `key=value` pairs; a repeated key keeps a single value.
+    #[default]
+    Flat,
+    /// Arrays and nested maps, e.g. `id=1&id=2`, `id[]=1` or `filter[name]=x`.
+    Nested,
+}
+
+/// Debug logging of request and response bodies of selected endpoints.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct BodyLogging {
+    /// Request paths, e.g. `/api/services/foo/v1/bar`, whose bodies are logged.
+    pub paths: Vec<String>,
+    /// JSON pointers, e.g. `/credentials/password`, of values masked in logged bodies.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redacted: Vec<String>,
+}
+
+/// Response compression settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CompressionConfig {
+    /// Encodings the server may apply (`gzip` or `deflate`); others advertised by clients
+    /// are ignored.
+    #[serde(default = "default_encodings")]
+    pub encodings: Vec<String>,
+    /// Responses smaller than this many bytes are sent uncompressed.
+    #[serde(default = "default_min_compressed_size")]
+    pub min_size: usize,
+}
+
+/// Encodings supported by the `Compress` middleware with the actix-web features enabled
+/// for this crate.
+const SUPPORTED_ENCODINGS: &[&str] = &["gzip", "deflate"];
+
+fn default_encodings() -> Vec<String> {
+    vec!["gzip".to_owned()]
+}
+
+fn default_min_compressed_size() -> usize {
+    1_024
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            encodings: default_encodings(),
+            min_size: default_min_compressed_size(),
+        }
+    }
+}
+
+/// Headers announcing that a deprecated endpoint is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum DeprecationHeader {
+    /// `Warning: 299 <agent> "..."` with the deprecation notice.
+    #[default]
+    Warning,
+    /// `Sunset` with the discontinuation date, if one is set.
+    Sunset,
+    Both,
+}
+
+/// Trailing-slash handling of request paths. Endpoints are registered without a trailing
+/// slash, so with `Off`, the default, `/api/foo/` does not resolve to the `/api/foo`
+/// endpoint; with `Trim` or `Always`, both forms do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum PathNormalization {
+    /// Paths are routed as requested.
+    #[default]
+    Off,
+    /// Trailing slashes are removed and repeated slashes merged.
+    Trim,
+    /// A trailing slash is added and repeated slashes merged, for services registered
+    /// with trailing slashes via `AppConfigurator`; the api scope still trims it.
+    Always,
+}
+
+impl PathNormalization {
+    fn middleware(self) -> Condition<NormalizePath> {
+        let trailing_slash = match self {
+            PathNormalization::Always => TrailingSlash::Always,
+            PathNormalization::Off | PathNormalization::Trim => TrailingSlash::Trim,
+        };
+        Condition::new(
+            self != PathNormalization::Off,
+            NormalizePath::new(trailing_slash),
+        )
+    }
+
+    fn api_middleware(self) -> Condition<NormalizePath> {
+        Condition::new(self == PathNormalization::Always, NormalizePath::trim())
+    }
+}
+
+/// Security headers added to every response that does not already set them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct SecurityHeaders {
+    #[serde(default = "default_true")]
+    pub content_type_nosniff: bool,
+    #[serde(
+        default = "default_frame_options",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub frame_options: Option<String>,
+    /// `Strict-Transport-Security` max age in seconds; only sent by TLS servers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hsts_max_age: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_security_policy: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_frame_options() -> Option<String> {
+    Some("DENY".to_owned())
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            content_type_nosniff: true,
+            frame_options: default_frame_options(),
+            hsts_max_age: None,
+            content_security_policy: None,
+        }
+    }
+}
+
+impl SecurityHeaders {
+    fn middleware(headers: Option<&Self>, tls: bool) -> Condition<DefaultHeaders> {
+        let mut middleware = DefaultHeaders::new();
+        if let Some(headers) = headers {
+            if headers.content_type_nosniff {
+                middleware = middleware.add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+            }
+            if let Some(ref frame_options) = headers.frame_options {
+                middleware = middleware.add((header::X_FRAME_OPTIONS, frame_options.as_str()));
+            }
+            if let Some(max_age) = headers.hsts_max_age.filter(|_| tls) {
+                middleware = middleware.add((
+                    header::STRICT_TRANSPORT_SECURITY,
+                    format!("max-age={}", max_age),
+                ));
+            }
+            if let Some(ref policy) = headers.content_security_policy {
+                middleware = middleware.add((header::CONTENT_SECURITY_POLICY, policy.as_str()));
+            }
+        }
+        Condition::new(headers.is_some(), middleware)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[non_exhaustive]
 pub struct WebServerConfig {
     pub listen_address: SocketAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub allow_origin: Option<AllowOrigin>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expose_headers: Vec<String>,
+    /// Allowed origins for requests whose path starts with the key, such as `/api/public`,
+    /// overriding `allow_origin`. Prefixes match whole path segments; when several
+    /// prefixes match a request, the longest one wins.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub path_allow_origins: BTreeMap<String, AllowOrigin>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub json_payload_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_query_len: Option<usize>,
+    #[serde(default = "default_api_prefix")]
+    pub api_prefix: String,
+    #[serde(default)]
+    pub error_format: ErrorFormat,
+    #[serde(default)]
+    pub error_field_case: ErrorFieldCase,
+    #[serde(default)]
+    pub decompress_requests: bool,
+    /// Rejects JSON request bodies containing fields the endpoint does not expect.
+    #[serde(default)]
+    pub deny_unknown_fields: bool,
+    #[serde(default)]
+    pub query_format: QueryFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security_headers: Option<SecurityHeaders>,
+    #[serde(skip)]
+    pub configure_app: Option<AppConfigurator>,
+    /// Logs bodies of the listed endpoints at `debug` level; bodies larger than the
+    /// JSON payload limit are not logged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_logging: Option<BodyLogging>,
+    /// Compresses responses when set. Already compressed content types, such as images
+    /// or archives, are never compressed again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionConfig>,
+    #[serde(default)]
+    pub deprecation_header: DeprecationHeader,
+    /// Also sends `Deprecation: true` with responses of deprecated endpoints, regardless
+    /// of `deprecation_header`.
+    #[serde(default)]
+    pub deprecation_flag: bool,
+    /// Agent token of the `Warning` header; `-` if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning_agent: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// Header from which client addresses are taken when the peer is a trusted proxy.
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+    /// Scheme and host (e.g. `https://api.example.com`) used for absolute redirect locations
+    /// instead of the ones reported in request headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_base_url: Option<String>,
+    #[serde(default)]
+    pub path_normalization: PathNormalization,
+    /// Statuses whose empty-bodied responses are left as is. By default, `400` and `404`
+    /// responses without a body, whether produced by the framework or by a handler, are
+    /// rewritten into a structured error body.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub raw_error_statuses: Vec<u16>,
+    /// Maximum number of pending connections queued by the listening socket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backlog: Option<u32>,
+    /// Sets `SO_REUSEADDR` on the listening socket, so that a restarted server can rebind
+    /// while connections of the previous one linger in `TIME_WAIT`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reuse_address: Option<bool>,
+    /// Sets `SO_REUSEPORT` on the listening socket; supported on Unix only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reuse_port: Option<bool>,
+    /// Requests arriving while this many are already being handled are rejected
+    /// with `503 Service Unavailable`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_in_flight_requests: Option<usize>,
+    /// Value of the `Retry-After` header (in seconds) sent with shed requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overload_retry_after: Option<u64>,
+    /// Serves `/favicon.ico` and `/robots.txt` when set; static files registered for the
+    /// same routes take precedence.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub well_known_files: Option<WellKnownFiles>,
+    /// Time (in milliseconds) after which an endpoint handler fails with `504 Gateway Timeout`.
+    /// Clients may shorten it with the `X-Deadline` or `grpc-timeout` headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_timeout: Option<u64>,
+    #[cfg(feature = "tls")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<crate::TlsConfig>,
+}
+
+fn default_api_prefix() -> String {
+    "api".to_owned()
 }
 
 impl WebServerConfig {
@@ -32,10 +313,75 @@ impl WebServerConfig {
         Self {
             listen_address,
             allow_origin: None,
+            expose_headers: Vec::new(),
+            path_allow_origins: BTreeMap::new(),
             json_payload_size: None,
+            max_query_len: None,
+            api_prefix: default_api_prefix(),
+            error_format: ErrorFormat::default(),
+            error_field_case: ErrorFieldCase::default(),
+            decompress_requests: false,
+            deny_unknown_fields: false,
+            query_format: QueryFormat::default(),
+            security_headers: None,
+            configure_app: None,
+            body_logging: None,
+            compression: None,
+            deprecation_header: DeprecationHeader::default(),
+            deprecation_flag: false,
+            warning_agent: None,
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::default(),
+            public_base_url: None,
+            path_normalization: PathNormalization::default(),
+            raw_error_statuses: Vec::new(),
+            backlog: None,
+            reuse_address: None,
+            reuse_port: None,
+            max_in_flight_requests: None,
+            overload_retry_after: None,
+            well_known_files: None,
+            request_timeout: None,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
+    /// Sets `json_payload_size` to `kib` kibibytes (1024 bytes). Fails if `kib` is zero
+    /// or the size in bytes overflows `usize`.
+    pub fn json_payload_kib(self, kib: usize) -> io::Result<Self> {
+        self.with_json_payload_units(kib, 1 << 10)
+    }
+
+    /// Sets `json_payload_size` to `mib` mebibytes (1024 KiB). Fails if `mib` is zero
+    /// or the size in bytes overflows `usize`.
+    pub fn json_payload_mib(self, mib: usize) -> io::Result<Self> {
+        self.with_json_payload_units(mib, 1 << 20)
+    }
+
+    fn with_json_payload_units(mut self, units: usize, unit_size: usize) -> io::Result<Self> {
+        let size = units.checked_mul(unit_size).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "JSON payload size of {} x {} bytes overflows",
+                    units, unit_size
+                ),
+            )
+        })?;
+        check_json_payload_size(size)?;
+        self.json_payload_size = Some(size);
+        Ok(self)
+    }
+
+    fn api_scope(&self) -> actix_web::Scope {
+        web::scope(self.api_prefix.trim_matches('/'))
+    }
+
+    fn effective_json_payload_size(&self) -> usize {
+        self.json_payload_size.unwrap_or(DEFAULT_JSON_PAYLOAD_SIZE)
+    }
+
     fn json_config(&self) -> JsonConfig {
         let config = JsonConfig::default();
 
@@ -46,10 +392,257 @@ impl WebServerConfig {
         }
     }
 
-    fn cors_factory(&self) -> Cors {
-        self.allow_origin
-            .clone()
-            .map_or_else(Cors::default, Cors::from)
+    fn request_config(&self) -> RequestConfig {
+        RequestConfig::new(
+            self.max_query_len,
+            self.effective_json_payload_size(),
+            self.decompress_requests,
+            self.deny_unknown_fields,
+            self.query_format,
+        )
+    }
+
+    fn deprecation_headers(&self) -> DeprecationHeaders {
+        DeprecationHeaders::new(
+            self.deprecation_header,
+            self.deprecation_flag,
+            self.warning_agent.clone(),
+        )
+    }
+
+    /// Binds the listening socket with the configured options; the platform defaults are
+    /// kept for options left unset.
+    fn bind_listener(&self) -> io::Result<TcpListener> {
+        if self.backlog.is_none() && self.reuse_address.is_none() && self.reuse_port.is_none() {
+            return TcpListener::bind(self.listen_address);
+        }
+
+        let address = self.listen_address;
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        #[cfg(unix)]
+        socket.set_reuse_address(self.reuse_address.unwrap_or(true))?;
+        #[cfg(not(unix))]
+        if let Some(reuse_address) = self.reuse_address {
+            socket.set_reuse_address(reuse_address)?;
+        }
+        if let Some(reuse_port) = self.reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(reuse_port)?;
+            #[cfg(not(unix))]
+            if reuse_port {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "SO_REUSEPORT is not supported on this platform",
+                ));
+            }
+        }
+        socket.bind(&address.into())?;
+        let backlog = self.backlog.unwrap_or(DEFAULT_BACKLOG);
+        socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+        Ok(socket.into())
+    }
+
+    fn cors_factory(&self) -> ScopedCors {
+        let scoped = self
+            .path_allow_origins
+            .iter()
+            .map(|(prefix, origin)| (prefix.clone(), self.cors(Some(origin))));
+        ScopedCors::new(self.cors(self.allow_origin.as_ref()), scoped)
+    }
+
+    fn cors(&self, allow_origin: Option<&AllowOrigin>) -> Cors {
+        let cors = allow_origin.map_or_else(Cors::default, Cors::from);
+
+        if self.expose_headers.is_empty() {
+            cors
+        } else {
+            cors.expose_headers(self.expose_headers.clone())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct StaticFileConfig {
+    pub route: String,
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl StaticFileConfig {
+    pub fn new(route: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            route: route.into(),
+            path: path.into(),
+            content_type: None,
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    fn load(&self) -> io::Result<StaticFile> {
+        let content = fs::read(&self.path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Unable to read static file {}: {}", self.path.display(), e),
+            )
+        })?;
+        let content_type = self.content_type.clone().unwrap_or_else(|| {
+            let content_type = match self.path.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => "application/json",
+                Some("yaml") | Some("yml") => "application/yaml",
+                Some("html") => "text/html; charset=utf-8",
+                _ => "text/plain; charset=utf-8",
+            };
+            content_type.to_owned()
+        });
+
+        Ok(StaticFile {
+            route: self.route.clone(),
+            content: Bytes::from(content),
+            content_type,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StaticFile {
+    route: String,
+    content: Bytes,
+    content_type: String,
+}
+
+impl StaticFile {
+    fn register(&self, config: &mut ServiceConfig) {
+        let content = self.content.clone();
+        let content_type = self.content_type.clone();
+        config.route(
+            &self.route,
+            web::get().to(move || {
+                let response = HttpResponse::Ok()
+                    .append_header((header::CONTENT_TYPE, content_type.clone()))
+                    .body(content.clone());
+                async move { response }
+            }),
+        );
+    }
+}
+
+const FAVICON_ROUTE: &str = "/favicon.ico";
+const ROBOTS_TXT_ROUTE: &str = "/robots.txt";
+
+/// Responses to `/favicon.ico` and `/robots.txt`, which browsers and crawlers request on
+/// their own. Paths left unset are answered with `204 No Content` rather than a `404` error.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct WellKnownFiles {
+    /// Icon file served as `/favicon.ico`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<PathBuf>,
+    /// Contents of `/robots.txt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub robots_txt: Option<String>,
+}
+
+impl WellKnownFiles {
+    fn load(&self) -> io::Result<Vec<StaticFile>> {
+        let mut files = Vec::new();
+        if let Some(ref path) = self.favicon {
+            let favicon =
+                StaticFileConfig::new(FAVICON_ROUTE, path).with_content_type("image/x-icon");
+            files.push(favicon.load()?);
+        }
+        if let Some(ref robots_txt) = self.robots_txt {
+            files.push(StaticFile {
+                route: ROBOTS_TXT_ROUTE.to_owned(),
+                content: Bytes::from(robots_txt.clone()),
+                content_type: "text/plain; charset=utf-8".to_owned(),
+            });
+        }
+        Ok(files)
+    }
+
+    fn register(files: &[StaticFile], config: &mut ServiceConfig) {
+        for route in [FAVICON_ROUTE, ROBOTS_TXT_ROUTE] {
+            match files.iter().find(|file| file.route == route) {
+                Some(file) => file.register(config),
+                None => {
+                    config.route(
+                        route,
+                        web::get().to(|| async { HttpResponse::NoContent().finish() }),
+                    );
+                }
+            }
+        }
+    }
+}
+
+type ShutdownFn = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+#[derive(Clone)]
+pub struct ShutdownHook {
+    inner: Arc<Mutex<Option<ShutdownFn>>>,
+}
+
+impl fmt::Debug for ShutdownHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownHook").finish()
+    }
+}
+
+impl ShutdownHook {
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: FnOnce() -> BoxFuture<'static, ()> + Send + 'static,
+    {
+        Self {
+            inner: Arc::new(Mutex::new(Some(Box::new(hook)))),
+        }
+    }
+
+    async fn run(&self) {
+        let hook = self.inner.lock().unwrap().take();
+        if let Some(hook) = hook {
+            hook().await;
+        }
+    }
+}
+
+type ConfigureFn = dyn Fn(&mut ServiceConfig) + Send + Sync;
+
+/// Registers extra services, e.g. `/` or `/.well-known/...` routes, next to the api
+/// scope of a server. It is invoked once per worker.
+#[derive(Clone)]
+pub struct AppConfigurator {
+    inner: Arc<ConfigureFn>,
+}
+
+impl fmt::Debug for AppConfigurator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppConfigurator").finish()
+    }
+}
+
+impl AppConfigurator {
+    pub fn new<F>(configure: F) -> Self
+    where
+        F: Fn(&mut ServiceConfig) + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(configure),
+        }
+    }
+
+    fn configure(&self, config: &mut ServiceConfig) {
+        (self.inner)(config);
     }
 }
 
@@ -59,11 +652,32 @@ pub struct ApiManagerConfig {
     pub servers: HashMap<ApiAccess, WebServerConfig>,
     pub api_aggregator: ApiAggregator,
     pub server_restart_retry_timeout: u64,
+    /// Number of times a failed server start is retried after the first attempt, so `0`
+    /// means a single attempt and the default of `19` means 20 attempts. This used to be
+    /// the total number of attempts, with `0` never starting the server.
     pub server_restart_max_retries: u16,
     pub disable_signals: bool,
+    pub maintenance_retry_after: u64,
+    pub maintenance_exempt_paths: Vec<String>,
+    pub static_files: Vec<StaticFileConfig>,
+    pub on_shutdown: Option<ShutdownHook>,
+    /// Deploy-scoped version sent in the `X-Api-Version` header of every response and
+    /// prefixed to response ETags, so that clients can drop responses cached before a new
+    /// deploy.
+    pub version_tag: Option<String>,
+    /// Milliseconds without further `UpdateEndpoints` to wait before restarting servers;
+    /// superseded updates received meanwhile, or during a restart, are dropped.
+    pub reload_quiet_period: u64,
+    /// Name prefixed to the log lines of the manager, telling apart several managers
+    /// running in one process.
+    pub instance_name: Option<String>,
 }
 
 impl ApiManagerConfig {
+    pub fn builder() -> ApiManagerConfigBuilder {
+        ApiManagerConfigBuilder::default()
+    }
+
     pub fn new(
         servers: HashMap<ApiAccess, WebServerConfig>,
         api_aggregator: ApiAggregator,
@@ -75,6 +689,8 @@ impl ApiManagerConfig {
         }
     }
 
+    /// Sets the delay between server start attempts, in milliseconds, and the number of
+    /// retries after the first attempt (see `server_restart_max_retries`).
     pub fn with_retries(mut self, timeout: u64, max_retries: u16) -> Self {
         self.server_restart_retry_timeout = timeout;
         self.server_restart_max_retries = max_retries;
@@ -85,6 +701,172 @@ impl ApiManagerConfig {
         self.disable_signals = true;
         self
     }
+
+    pub fn with_maintenance(mut self, retry_after: u64, exempt_paths: Vec<String>) -> Self {
+        self.maintenance_retry_after = retry_after;
+        self.maintenance_exempt_paths = exempt_paths;
+        self
+    }
+
+    pub fn with_static_file(mut self, static_file: StaticFileConfig) -> Self {
+        self.static_files.push(static_file);
+        self
+    }
+
+    pub fn with_shutdown_hook<F>(mut self, hook: F) -> Self
+    where
+        F: FnOnce() -> BoxFuture<'static, ()> + Send + 'static,
+    {
+        self.on_shutdown = Some(ShutdownHook::new(hook));
+        self
+    }
+
+    pub fn with_reload_quiet_period(mut self, quiet_period: u64) -> Self {
+        self.reload_quiet_period = quiet_period;
+        self
+    }
+
+    pub fn with_version_tag<S: Into<String>>(mut self, version_tag: S) -> Self {
+        self.version_tag = Some(version_tag.into());
+        self
+    }
+
+    pub fn with_instance_name<S: Into<String>>(mut self, instance_name: S) -> Self {
+        self.instance_name = Some(instance_name.into());
+        self
+    }
+
+    fn log_prefix(&self) -> String {
+        self.instance_name
+            .as_ref()
+            .map_or_else(String::new, |name| format!("[{}] ", name))
+    }
+
+    /// Checks the configuration for mistakes that would only show up at runtime, e.g.
+    /// servers sharing a listen address or a public JSON payload limit looser than
+    /// the private one.
+    pub fn validate(&self) -> io::Result<()> {
+        self.check_listen_addresses()?;
+
+        if let Some(internal) = self.servers.get(&ApiAccess::Internal) {
+            if !internal.listen_address.ip().is_loopback() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Internal api server must listen on a loopback address, got {}",
+                        internal.listen_address
+                    ),
+                ));
+            }
+        }
+
+        for (access, server_config) in &self.servers {
+            if let Some(size) = server_config.json_payload_size {
+                check_json_payload_size(size).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{} for {} api server", e, access))
+                })?;
+            }
+            let encodings = server_config
+                .compression
+                .iter()
+                .flat_map(|compression| &compression.encodings);
+            for encoding in encodings {
+                if !SUPPORTED_ENCODINGS
+                    .iter()
+                    .any(|supported| supported.eq_ignore_ascii_case(encoding))
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Unsupported compression encoding `{}` for {} api server, expected one of {:?}",
+                            encoding, access, SUPPORTED_ENCODINGS
+                        ),
+                    ));
+                }
+            }
+        }
+
+        // `DefaultHeaders` panics on invalid values while building the app of each worker.
+        if let Some(ref version_tag) = self.version_tag {
+            check_header_value(API_VERSION.as_str(), version_tag)?;
+        }
+        for server_config in self.servers.values() {
+            if let Some(ref headers) = server_config.security_headers {
+                if let Some(ref frame_options) = headers.frame_options {
+                    check_header_value(header::X_FRAME_OPTIONS.as_str(), frame_options)?;
+                }
+                if let Some(ref policy) = headers.content_security_policy {
+                    check_header_value(header::CONTENT_SECURITY_POLICY.as_str(), policy)?;
+                }
+            }
+        }
+
+        let public = self.servers.get(&ApiAccess::Public);
+        let private = self.servers.get(&ApiAccess::Private);
+        if let (Some(public), Some(private)) = (public, private) {
+            let public_limit = public.effective_json_payload_size();
+            let private_limit = private.effective_json_payload_size();
+            if public_limit > private_limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Public api JSON payload limit ({} bytes) is looser than the private one ({} bytes)",
+                        public_limit, private_limit
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_listen_addresses(&self) -> io::Result<()> {
+        let mut addresses: Vec<(SocketAddr, ApiAccess)> = Vec::new();
+        for (&access, server_config) in &self.servers {
+            let address = server_config.listen_address;
+            // Port 0 binds to a distinct ephemeral port each time.
+            if address.port() == 0 {
+                continue;
+            }
+            let overlaps = |other: &SocketAddr| {
+                other.port() == address.port()
+                    && (other.ip() == address.ip()
+                        || other.ip().is_unspecified()
+                        || address.ip().is_unspecified())
+            };
+            if let Some(&(other_address, other)) =
+                addresses.iter().find(|(other, _)| overlaps(other))
+            {
+                let msg = format!(
+                    "{} and {} api servers are configured with overlapping listen addresses {} and {}",
+                    other, access, other_address, address
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            addresses.push((address, access));
+        }
+
+        Ok(())
+    }
+}
+
+fn check_json_payload_size(size: usize) -> io::Result<()> {
+    if size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "JSON payload size must be positive",
+        ));
+    }
+    Ok(())
+}
+
+fn check_header_value(name: &str, value: &str) -> io::Result<()> {
+    header::HeaderValue::from_str(value).map(drop).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid value {:?} for the `{}` header", value, name),
+        )
+    })
 }
 
 impl Default for ApiManagerConfig {
@@ -93,20 +875,114 @@ impl Default for ApiManagerConfig {
             servers: HashMap::new(),
             api_aggregator: ApiAggregator::default(),
             server_restart_retry_timeout: 500,
-            server_restart_max_retries: 20,
+            server_restart_max_retries: 19,
             disable_signals: false,
+            maintenance_retry_after: 60,
+            maintenance_exempt_paths: Vec::new(),
+            static_files: Vec::new(),
+            on_shutdown: None,
+            version_tag: None,
+            reload_quiet_period: 0,
+            instance_name: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct ApiManagerConfigBuilder {
+    public: Option<WebServerConfig>,
+    private: Option<WebServerConfig>,
+    internal: Option<WebServerConfig>,
+    api_aggregator: ApiAggregator,
+}
+
+impl ApiManagerConfigBuilder {
+    pub fn public(mut self, config: WebServerConfig) -> Self {
+        self.public = Some(config);
+        self
+    }
+
+    pub fn private(mut self, config: WebServerConfig) -> Self {
+        self.private = Some(config);
+        self
+    }
+
+    pub fn internal(mut self, config: WebServerConfig) -> Self {
+        self.internal = Some(config);
+        self
+    }
+
+    pub fn api_aggregator(mut self, api_aggregator: ApiAggregator) -> Self {
+        self.api_aggregator = api_aggregator;
+        self
+    }
+
+    pub fn build(self) -> ApiManagerConfig {
+        let servers = [
+            (ApiAccess::Public, self.public),
+            (ApiAccess::Private, self.private),
+            (ApiAccess::Internal, self.internal),
+        ]
+        .into_iter()
+        .filter_map(|(access, config)| Some((access, config?)))
+        .collect();
+
+        ApiManagerConfig::new(servers, self.api_aggregator)
+    }
+}
+
+/// Clones of a factory-backed update share the future, so each of them resolves to the
+/// same endpoints.
+#[derive(Clone)]
+struct EndpointsFactory {
+    inner: Shared<BoxFuture<'static, Vec<(String, ApiBuilder)>>>,
+}
+
+impl fmt::Debug for EndpointsFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EndpointsFactory").finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UpdateEndpoints {
     endpoints: Vec<(String, ApiBuilder)>,
+    factory: Option<EndpointsFactory>,
 }
 
 impl UpdateEndpoints {
     pub fn new(endpoints: Vec<(String, ApiBuilder)>) -> Self {
-        Self { endpoints }
+        Self {
+            endpoints,
+            factory: None,
+        }
+    }
+
+    /// Builds the endpoints lazily: the manager awaits `future` while handling the update,
+    /// before restarting the servers. Endpoints built this way are not reported by
+    /// `updated_paths`.
+    pub fn from_future<F>(future: F) -> Self
+    where
+        F: Future<Output = Vec<(String, ApiBuilder)>> + Send + 'static,
+    {
+        Self {
+            endpoints: Vec::new(),
+            factory: Some(EndpointsFactory {
+                inner: future.boxed().shared(),
+            }),
+        }
+    }
+
+    /// Adds `api` under `name` and every alias, so that all of them are reported by
+    /// `updated_paths`.
+    pub fn with_aliases(mut self, name: &str, aliases: &[&str], api: ApiBuilder) -> Self {
+        self.endpoints.extend(
+            aliases
+                .iter()
+                .map(|alias| ((*alias).to_owned(), api.clone())),
+        );
+        self.endpoints.push((name.to_owned(), api));
+        self
     }
 
     pub fn updated_paths(&self) -> impl Iterator<Item = &str> {
@@ -117,32 +993,83 @@ impl UpdateEndpoints {
     pub fn into_endpoints(self) -> Vec<(String, ApiBuilder)> {
         self.endpoints
     }
+
+    async fn resolve(self) -> Vec<(String, ApiBuilder)> {
+        let mut endpoints = self.endpoints;
+        if let Some(factory) = self.factory {
+            endpoints.extend(factory.inner.await);
+        }
+        endpoints
+    }
 }
 
+/// Runs `action` once and then retries it up to `max_retries` times, so `0` means a
+/// single attempt. If every attempt fails, the error of the last one is returned.
 async fn with_retries<T>(
     mut action: impl FnMut() -> io::Result<T>,
     description: String,
-    attempts: u16,
+    log_prefix: &str,
+    max_retries: u16,
     timeout: u64,
 ) -> io::Result<T> {
     let timeout = Duration::from_millis(timeout);
+    let attempts = u32::from(max_retries) + 1;
+    let mut last_error: Option<(String, u16)> = None;
+    let mut attempt = 1;
+
+    loop {
+        log::trace!("{}{} (attempt #{})", log_prefix, description, attempt);
+        let e = match action() {
+            Ok(value) => {
+                log_repeated_failures(log_prefix, &description, last_error.as_ref());
+                return Ok(value);
+            }
+            Err(e) => e,
+        };
 
-    for attempt in 1..=attempts {
-        log::trace!("{} (attempt #{})", description, attempt);
-        match action() {
-            Ok(value) => return Ok(value),
-            Err(e) => {
-                log::warn!("{} (attempt #{}) failed: {}", description, attempt, e);
-                sleep(timeout).await;
+        let error = e.to_string();
+        match last_error {
+            Some((ref last, ref mut repeats)) if *last == error => *repeats += 1,
+            _ => {
+                log_repeated_failures(log_prefix, &description, last_error.as_ref());
+                log::warn!(
+                    "{}{} (attempt #{}) failed: {}",
+                    log_prefix,
+                    description,
+                    attempt,
+                    error
+                );
+                last_error = Some((error, 1));
             }
         }
+
+        if attempt == attempts {
+            log_repeated_failures(log_prefix, &description, last_error.as_ref());
+            log::error!(
+                "{}Cannot complete {} after {} attempts",
+                log_prefix,
+                description,
+                attempts
+            );
+            return Err(e);
+        }
+        attempt += 1;
+        sleep(timeout).await;
     }
+}
 
-    let msg = format!(
-        "Cannot complete {} after {} attempts",
-        description, attempts
-    );
-    Err(io::Error::new(io::ErrorKind::Other, msg))
+fn log_repeated_failures(log_prefix: &str, description: &str, last_error: Option<&(String, u16)>) {
+    if let Some((error, repeats)) = last_error {
+        if *repeats > 1 {
+            log::warn!(
+                "{}{} failed {} times in a row: {}",
+                log_prefix,
+                description,
+                repeats,
+                error
+            );
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -156,69 +1083,262 @@ impl ServerHandle {
     }
 }
 
+type DataRegistrar = dyn Fn(&mut ServiceConfig) + Send + Sync;
+
+#[derive(Clone, Default)]
+pub struct SharedData {
+    registrars: Vec<Arc<DataRegistrar>>,
+}
+
+impl fmt::Debug for SharedData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedData")
+            .field("len", &self.registrars.len())
+            .finish()
+    }
+}
+
+impl SharedData {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<T: Send + Sync + 'static>(&mut self, data: T) -> Data<T> {
+        let data = Data::new(data);
+        let registered = data.clone();
+        self.registrars
+            .push(Arc::new(move |config: &mut ServiceConfig| {
+                config.app_data(registered.clone());
+            }));
+        data
+    }
+
+    fn register(&self, config: &mut ServiceConfig) {
+        for registrar in &self.registrars {
+            registrar(config);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AppState {
+    shared_data: SharedData,
+    maintenance: MaintenanceMode,
+    api_key_auth: Option<ApiKeyAuth>,
+    idempotency: Option<Idempotency>,
+    static_files: Vec<StaticFile>,
+    version_tag: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ManagerEvent {
+    /// Servers have been (re)started; `routes` lists `METHOD scope/name` per access level.
+    Started {
+        instance_name: Option<String>,
+        addresses: HashMap<ApiAccess, SocketAddr>,
+        routes: HashMap<ApiAccess, Vec<String>>,
+    },
+    Stopped {
+        instance_name: Option<String>,
+    },
+}
+
 #[derive(Debug)]
 pub struct ApiManager {
     config: ApiManagerConfig,
     servers: Vec<ServerHandle>,
     endpoints: Vec<(String, ApiBuilder)>,
+    shared_data: SharedData,
+    maintenance: MaintenanceMode,
+    api_key_auth: HashMap<ApiAccess, ApiKeyAuth>,
+    idempotency: HashMap<ApiAccess, Idempotency>,
+    event_subscribers: Vec<mpsc::UnboundedSender<ManagerEvent>>,
+    runtime: Option<Handle>,
+    start_on_run: bool,
+    log_prefix: Arc<str>,
 }
 
 impl ApiManager {
     pub fn new(config: ApiManagerConfig) -> Self {
+        let maintenance = MaintenanceMode::new(
+            config.maintenance_retry_after,
+            config.maintenance_exempt_paths.clone(),
+        );
+        let log_prefix = Arc::from(config.log_prefix());
         Self {
             config,
             servers: Vec::new(),
             endpoints: Vec::new(),
+            shared_data: SharedData::new(),
+            maintenance,
+            api_key_auth: HashMap::new(),
+            idempotency: HashMap::new(),
+            event_subscribers: Vec::new(),
+            runtime: None,
+            start_on_run: false,
+            log_prefix,
+        }
+    }
+
+    /// Creates a manager whose servers start serving `endpoints` as soon as it runs,
+    /// without waiting for the first `UpdateEndpoints`.
+    ///
+    /// Fails if the config is invalid (see `ApiManagerConfig::validate`) or several services
+    /// share a name.
+    pub fn with_initial_endpoints(
+        config: ApiManagerConfig,
+        endpoints: Vec<(String, ApiBuilder)>,
+    ) -> io::Result<Self> {
+        config.validate()?;
+        let mut names = HashSet::new();
+        for (name, _) in &endpoints {
+            if !names.insert(name.as_str()) {
+                let msg = format!("Service `{}` is registered more than once", name);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
         }
+
+        let mut manager = Self::new(config);
+        manager.endpoints = endpoints;
+        manager.start_on_run = true;
+        Ok(manager)
+    }
+
+    /// Services served in addition to those of the configured aggregator.
+    pub fn endpoints(&self) -> &[(String, ApiBuilder)] {
+        &self.endpoints
+    }
+
+    /// Returns a stream of server lifecycle events, e.g. to await a restart triggered by
+    /// `UpdateEndpoints` and check which routes it made live.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<ManagerEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.event_subscribers.push(tx);
+        rx
+    }
+
+    fn emit(&mut self, event: &ManagerEvent) {
+        self.event_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    pub fn with_api_key_auth(mut self, access: ApiAccess, auth: ApiKeyAuth) -> Self {
+        self.api_key_auth.insert(access, auth);
+        self
+    }
+
+    pub fn with_idempotency(mut self, access: ApiAccess, idempotency: Idempotency) -> Self {
+        self.idempotency.insert(access, idempotency);
+        self
+    }
+
+    /// Runs the tasks driving the servers on `runtime` instead of the current one. Each
+    /// server still runs its workers on their own threads.
+    pub fn with_runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    pub fn maintenance_mode(&self) -> MaintenanceMode {
+        self.maintenance.clone()
+    }
+
+    pub fn with_shared_data(mut self, shared_data: SharedData) -> Self {
+        self.shared_data = shared_data;
+        self
+    }
+
+    pub fn shared_data(&mut self) -> &mut SharedData {
+        &mut self.shared_data
     }
 
     async fn start_servers(
         &mut self,
         server_finished_tx: mpsc::Sender<io::Result<()>>,
-    ) -> io::Result<()> {
-        log::trace!("Servers start requested.");
+    ) -> io::Result<HashMap<ApiAccess, SocketAddr>> {
+        log::trace!("{}Servers start requested.", self.log_prefix);
+
+        self.config.validate()?;
 
         let disable_signals = self.config.disable_signals;
+        let static_files = self
+            .config
+            .static_files
+            .iter()
+            .map(StaticFileConfig::load)
+            .collect::<io::Result<Vec<_>>>()?;
+        // The aggregator is assembled once and shared by all servers and their workers;
+        // each server only selects its access scope from it.
+        let mut aggregator = self.config.api_aggregator.clone();
+        aggregator.extend(self.endpoints.iter().cloned());
+        let aggregator = Arc::new(aggregator);
+
         let start_servers = self.config.servers.iter().map(|(&access, server_config)| {
-            let mut aggregator = self.config.api_aggregator.clone();
-            aggregator.extend(self.endpoints.clone());
+            let aggregator = Arc::clone(&aggregator);
             let server_config = server_config.clone();
+            let state = AppState {
+                shared_data: self.shared_data.clone(),
+                maintenance: self.maintenance.clone(),
+                api_key_auth: self.api_key_auth.get(&access).cloned(),
+                idempotency: self.idempotency.get(&access).cloned(),
+                static_files: static_files.clone(),
+                version_tag: self.config.version_tag.clone(),
+            };
             let action_description = format!(
                 "starting {} api on {}",
                 access, server_config.listen_address
             );
+            let log_prefix = Arc::clone(&self.log_prefix);
 
             with_retries(
                 move || {
                     Self::start_server(
-                        aggregator.clone(),
+                        Arc::clone(&aggregator),
                         access,
                         server_config.clone(),
+                        state.clone(),
                         disable_signals,
+                        &log_prefix,
                     )
                 },
                 action_description,
+                &self.log_prefix,
                 self.config.server_restart_max_retries,
                 self.config.server_restart_retry_timeout,
             )
         });
         let servers = try_join_all(start_servers).await?;
+        let mut bound_addresses = HashMap::new();
+        let runtime = self.runtime.clone().unwrap_or_else(Handle::current);
 
         self.servers = servers
             .into_iter()
             .zip(&self.config.servers)
-            .map(|(server, (&access, server_config))| {
+            .map(|((server, bound_address), (&access, server_config))| {
+                bound_addresses.insert(access, bound_address);
                 let listen_addr = server_config.listen_address;
                 let mut server_finished = server_finished_tx.clone();
                 let handle = server.handle();
+                let log_prefix = Arc::clone(&self.log_prefix);
 
-                tokio::spawn(async move {
+                runtime.spawn(async move {
+                    // Client disconnects drop the request future inside the server and are
+                    // logged by the endpoint handler, so an error here is a bind or serve
+                    // failure.
                     let res = server.await;
                     if let Err(ref e) = res {
-                        log::error!("{} server on {} failed: {}", access, listen_addr, e);
+                        log::error!(
+                            "{}{} server on {} failed: {}",
+                            log_prefix,
+                            access,
+                            listen_addr,
+                            e
+                        );
                     } else if !server_finished.is_closed() {
                         log::info!(
-                            "{} server on {} terminated in response to a signal",
+                            "{}{} server on {} terminated in response to a signal",
+                            log_prefix,
                             access,
                             listen_addr
                         );
@@ -231,31 +1351,99 @@ impl ApiManager {
             })
             .collect();
 
-        Ok(())
+        let routes = self
+            .config
+            .servers
+            .keys()
+            .map(|&access| {
+                let routes = aggregator
+                    .routes(access)
+                    .map(|(scope, name, method, ..)| format!("{} {}/{}", method, scope, name))
+                    .collect();
+                (access, routes)
+            })
+            .collect();
+        self.emit(&ManagerEvent::Started {
+            instance_name: self.config.instance_name.clone(),
+            addresses: bound_addresses.clone(),
+            routes,
+        });
+
+        Ok(bound_addresses)
     }
 
     async fn stop_servers(&mut self) {
-        log::trace!("Servers stop requested.");
+        log::trace!("{}Servers stop requested.", self.log_prefix);
 
+        if self.servers.is_empty() {
+            return;
+        }
         join_all(self.servers.drain(..).map(ServerHandle::stop)).await;
+        self.emit(&ManagerEvent::Stopped {
+            instance_name: self.config.instance_name.clone(),
+        });
     }
 
     pub async fn run<S>(mut self, endpoints_rx: S) -> io::Result<()>
     where
         S: Stream<Item = UpdateEndpoints> + Unpin,
     {
-        let res = self.run_inner(endpoints_rx).await;
+        let res = self.run_inner(endpoints_rx, None).await;
         self.stop_servers().await;
-        log::info!("HTTP servers shut down");
+        log::info!("{}HTTP servers shut down", self.log_prefix);
+        self.run_shutdown_hook().await;
         res
     }
 
-    async fn run_inner<S>(&mut self, endpoints_rx: S) -> io::Result<()>
+    pub async fn run_with_ready<S>(
+        mut self,
+        endpoints_rx: S,
+        ready_tx: oneshot::Sender<HashMap<ApiAccess, SocketAddr>>,
+    ) -> io::Result<()>
     where
         S: Stream<Item = UpdateEndpoints> + Unpin,
     {
+        let res = self.run_inner(endpoints_rx, Some(ready_tx)).await;
+        self.stop_servers().await;
+        log::info!("{}HTTP servers shut down", self.log_prefix);
+        self.run_shutdown_hook().await;
+        res
+    }
+
+    async fn run_shutdown_hook(&self) {
+        if let Some(ref hook) = self.config.on_shutdown {
+            log::trace!("{}Running shutdown hook.", self.log_prefix);
+            hook.run().await;
+        }
+    }
+
+    async fn run_inner<S>(
+        &mut self,
+        endpoints_rx: S,
+        mut ready_tx: Option<oneshot::Sender<HashMap<ApiAccess, SocketAddr>>>,
+    ) -> io::Result<()>
+    where
+        S: Stream<Item = UpdateEndpoints> + Unpin,
+    {
+        if self.config.servers.is_empty() {
+            log::warn!(
+                "{}No api servers are configured, endpoint updates will not be served",
+                self.log_prefix
+            );
+        }
+
         let mut endpoints_rx = endpoints_rx.fuse();
+        // Each sender is guaranteed a slot, so the channel works without servers as well;
+        // its sender is kept alive here, so the loop then ends only with `endpoints_rx`.
         let mut server_finished_channel = mpsc::channel(self.config.servers.len());
+        if self.start_on_run {
+            let bound_addresses = self
+                .start_servers(server_finished_channel.0.clone())
+                .await?;
+            if let Some(ready_tx) = ready_tx.take() {
+                ready_tx.send(bound_addresses).ok();
+            }
+        }
 
         loop {
             futures::select! {
@@ -265,12 +1453,23 @@ impl ApiManager {
 
                 maybe_request = endpoints_rx.next() => {
                     if let Some(request) = maybe_request {
-                        log::info!("Server restart requested");
+                        let request = self.coalesce_updates(&mut endpoints_rx, request).await;
+                        log::info!("{}Server restart requested", self.log_prefix);
+                        let endpoints = request.resolve().await;
                         server_finished_channel = mpsc::channel(self.config.servers.len());
 
                         self.stop_servers().await;
-                        self.endpoints = request.endpoints;
-                        self.start_servers(server_finished_channel.0.clone()).await?;
+                        self.endpoints = endpoints;
+                        let bound_addresses =
+                            self.start_servers(server_finished_channel.0.clone()).await?;
+                        if let Some(ready_tx) = ready_tx.take() {
+                            ready_tx.send(bound_addresses).ok();
+                        }
+                        // The stream may have ended while updates were coalesced; `select!`
+                        // would skip its terminated branch and wait for the servers forever.
+                        if endpoints_rx.is_terminated() {
+                            return Ok(());
+                        }
                     } else {
                         return Ok(());
                     }
@@ -279,29 +1478,396 @@ impl ApiManager {
         }
     }
 
+    /// Returns the latest of the updates arriving until none arrives for the quiet period.
+    async fn coalesce_updates<S>(
+        &self,
+        endpoints_rx: &mut stream::Fuse<S>,
+        mut request: UpdateEndpoints,
+    ) -> UpdateEndpoints
+    where
+        S: Stream<Item = UpdateEndpoints> + Unpin,
+    {
+        let quiet_period = Duration::from_millis(self.config.reload_quiet_period);
+        loop {
+            let timeout = sleep(quiet_period).fuse();
+            futures::pin_mut!(timeout);
+            futures::select_biased! {
+                maybe_request = endpoints_rx.next() => match maybe_request {
+                    Some(next_request) => {
+                        log::debug!(
+                            "{}Endpoints update superseded by a newer one",
+                            self.log_prefix
+                        );
+                        request = next_request;
+                    }
+                    None => return request,
+                },
+                () = timeout => return request,
+            }
+        }
+    }
+
     fn start_server(
-        aggregator: ApiAggregator,
+        aggregator: Arc<ApiAggregator>,
         access: ApiAccess,
         server_config: WebServerConfig,
+        state: AppState,
         disable_signals: bool,
-    ) -> io::Result<actix_server::Server> {
+        log_prefix: &str,
+    ) -> io::Result<(actix_server::Server, SocketAddr)> {
         let listen_address = server_config.listen_address;
-        log::info!("Starting {} web api on {}", access, listen_address);
+        log::info!(
+            "{}Starting {} web api on {}",
+            log_prefix,
+            access,
+            listen_address
+        );
 
-        let listener = TcpListener::bind(listen_address)?;
-        let mut server_builder = HttpServer::new(move || {
+        #[cfg(feature = "tls")]
+        let tls = server_config
+            .tls
+            .as_ref()
+            .map(crate::TlsConfig::server_config)
+            .transpose()?;
+        let well_known_files = server_config
+            .well_known_files
+            .as_ref()
+            .map(WellKnownFiles::load)
+            .transpose()?;
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let listener = server_config.bind_listener()?;
+        let api_prefix = server_config.api_prefix.clone();
+        #[cfg(feature = "tls")]
+        let is_tls = tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        let is_tls = false;
+        let route_aggregator = Arc::clone(&aggregator);
+        let server_builder = HttpServer::new(move || {
             App::new()
                 .app_data(server_config.json_config())
+                .app_data(server_config.request_config())
+                .app_data(server_config.deprecation_headers())
+                .app_data(TrustedProxies {
+                    networks: server_config.trusted_proxies.clone(),
+                    header: server_config.forwarded_header,
+                })
+                .app_data(PublicBaseUrl(server_config.public_base_url.clone()))
+                .app_data(DefaultTimeout(
+                    server_config.request_timeout.map(Duration::from_millis),
+                ))
+                .configure(|config| state.shared_data.register(config))
+                .configure(|config| {
+                    for static_file in &state.static_files {
+                        static_file.register(config);
+                    }
+                })
+                .configure(|config| {
+                    if let Some(ref files) = well_known_files {
+                        WellKnownFiles::register(files, config);
+                    }
+                })
+                .configure(|config| {
+                    if let Some(ref configurator) = server_config.configure_app {
+                        configurator.configure(config);
+                    }
+                })
+                .wrap(error_handlers(&server_config.raw_error_statuses))
+                .wrap(Condition::new(
+                    server_config.body_logging.is_some(),
+                    BodyLogger::new(
+                        server_config.body_logging.clone().unwrap_or_default(),
+                        server_config.effective_json_payload_size(),
+                    ),
+                ))
+                .wrap(IdempotencyGuard::new(state.idempotency.clone()))
+                .wrap(ApiKeyGuard::new(state.api_key_auth.clone()))
+                .wrap(MaintenanceGuard::new(state.maintenance.clone()))
+                .wrap(LoadShedGuard::new(
+                    server_config.max_in_flight_requests,
+                    server_config.overload_retry_after,
+                    Arc::clone(&in_flight),
+                ))
+                .wrap(ErrorFormatter::new(
+                    server_config.error_format,
+                    server_config.error_field_case,
+                ))
+                // Rejections of the guards above carry CORS headers, so browsers can read them.
                 .wrap(server_config.cors_factory())
-                .wrap(error_handlers())
-                .service(aggregator.extend_backend(access, web::scope("api")))
-        })
-        .listen(listener)?;
+                .wrap(Condition::new(
+                    server_config.compression.is_some(),
+                    CompressionPolicy::new(server_config.compression.clone().unwrap_or_default()),
+                ))
+                .wrap(Condition::new(
+                    server_config.compression.is_some(),
+                    Compress::default(),
+                ))
+                .wrap(Condition::new(
+                    server_config.compression.is_some(),
+                    AcceptEncodingFilter::new(
+                        &server_config
+                            .compression
+                            .clone()
+                            .unwrap_or_default()
+                            .encodings,
+                    ),
+                ))
+                .wrap(Condition::new(
+                    state.version_tag.is_some(),
+                    VersionTag::new(state.version_tag.as_deref().unwrap_or_default()),
+                ))
+                .wrap(SecurityHeaders::middleware(
+                    server_config.security_headers.as_ref(),
+                    is_tls,
+                ))
+                .wrap(server_config.path_normalization.middleware())
+                .service(
+                    aggregator
+                        .extend_backend(access, server_config.api_scope())
+                        .wrap(server_config.path_normalization.api_middleware()),
+                )
+        });
+        #[cfg(feature = "tls")]
+        let mut server_builder = match tls {
+            Some(tls) => server_builder
+                .on_connect(crate::tls::on_connect)
+                .listen_rustls_0_21(listener, tls)?,
+            None => server_builder.listen(listener)?,
+        };
+        #[cfg(not(feature = "tls"))]
+        let mut server_builder = server_builder.listen(listener)?;
+
+        let bound_address = server_builder
+            .addrs()
+            .first()
+            .copied()
+            .unwrap_or(listen_address);
+
+        if log::log_enabled!(log::Level::Debug) {
+            let prefix = api_prefix.trim_matches('/');
+            let routes: Vec<_> = route_aggregator
+                .routes(access)
+                .map(|(scope, name, method, ..)| {
+                    format!("  {} /{}/{}/{}", method, prefix, scope, name)
+                })
+                .collect();
+            log::debug!(
+                "{}{} web api on {} exposes {} routes:\n{}",
+                log_prefix,
+                access,
+                bound_address,
+                routes.len(),
+                routes.join("\n")
+            );
+        }
 
         if disable_signals {
             server_builder = server_builder.disable_signals();
         }
 
-        Ok(server_builder.run())
+        Ok((server_builder.run(), bound_address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(public: &str, private: &str) -> ApiManagerConfig {
+        ApiManagerConfigBuilder::default()
+            .public(WebServerConfig::new(public.parse().unwrap()))
+            .private(WebServerConfig::new(private.parse().unwrap()))
+            .build()
+    }
+
+    #[test]
+    fn listen_addresses_overlap() {
+        assert!(config("127.0.0.1:8080", "127.0.0.1:8081")
+            .check_listen_addresses()
+            .is_ok());
+        assert!(config("127.0.0.1:8080", "127.0.0.1:8080")
+            .check_listen_addresses()
+            .is_err());
+        assert!(config("0.0.0.0:8080", "127.0.0.1:8080")
+            .check_listen_addresses()
+            .is_err());
+        assert!(config("127.0.0.1:0", "127.0.0.1:0")
+            .check_listen_addresses()
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_header_values() {
+        let config = config("127.0.0.1:8080", "127.0.0.1:8081");
+        assert!(config.clone().with_version_tag("1.2.0").validate().is_ok());
+        assert!(config
+            .clone()
+            .with_version_tag("1.2.0\nX-Injected: 1")
+            .validate()
+            .is_err());
+
+        let mut config = config;
+        let headers = SecurityHeaders {
+            content_security_policy: Some("default-src 'self'\r\n".to_owned()),
+            ..SecurityHeaders::default()
+        };
+        config
+            .servers
+            .get_mut(&ApiAccess::Public)
+            .unwrap()
+            .security_headers = Some(headers);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn json_payload_units() {
+        let server = || WebServerConfig::new("127.0.0.1:8080".parse().unwrap());
+        let size = |config: io::Result<WebServerConfig>| config.unwrap().json_payload_size;
+        assert_eq!(size(server().json_payload_kib(4)), Some(4_096));
+        assert_eq!(size(server().json_payload_mib(2)), Some(2 << 20));
+
+        assert!(server().json_payload_kib(0).is_err());
+        assert!(server().json_payload_mib(0).is_err());
+        assert!(server().json_payload_kib(usize::MAX).is_err());
+        assert!(server().json_payload_mib(usize::MAX >> 10).is_err());
+
+        let mut config = config("127.0.0.1:8080", "127.0.0.1:8081");
+        config
+            .servers
+            .get_mut(&ApiAccess::Private)
+            .unwrap()
+            .json_payload_size = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn retries_are_counted_after_the_first_attempt() {
+        let mut calls = 0;
+        let result: io::Result<()> = with_retries(
+            || {
+                calls += 1;
+                Err(io::Error::new(io::ErrorKind::AddrInUse, "busy"))
+            },
+            "binding".to_owned(),
+            "",
+            0,
+            0,
+        )
+        .await;
+        assert_eq!(calls, 1);
+        assert_eq!(result.unwrap_err().to_string(), "busy");
+
+        let mut calls = 0;
+        let result: io::Result<()> = with_retries(
+            || {
+                calls += 1;
+                Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("attempt {}", calls),
+                ))
+            },
+            "binding".to_owned(),
+            "",
+            2,
+            0,
+        )
+        .await;
+        assert_eq!(calls, 3);
+        assert_eq!(result.unwrap_err().to_string(), "attempt 3");
+
+        let mut calls = 0;
+        let result = with_retries(
+            || {
+                calls += 1;
+                if calls < 2 {
+                    Err(io::Error::new(io::ErrorKind::AddrInUse, "busy"))
+                } else {
+                    Ok(calls)
+                }
+            },
+            "binding".to_owned(),
+            "",
+            5,
+            0,
+        )
+        .await;
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn trailing_slashes_are_normalized() {
+        use actix_web::test::{call_service, init_service, TestRequest};
+
+        fn handler(_: ()) -> futures::future::Ready<crate::Result<u8>> {
+            futures::future::ok(1)
+        }
+
+        let mut builder = ApiBuilder::new();
+        builder.public_scope().endpoint("v1/block", handler);
+        let mut aggregator = ApiAggregator::new();
+        aggregator.insert("explorer", builder);
+
+        for (normalization, resolves_with_slash) in [
+            (PathNormalization::Off, false),
+            (PathNormalization::Trim, true),
+            (PathNormalization::Always, true),
+        ] {
+            let app = init_service(
+                App::new().wrap(normalization.middleware()).service(
+                    aggregator
+                        .extend_backend(ApiAccess::Public, web::scope("api"))
+                        .wrap(normalization.api_middleware()),
+                ),
+            )
+            .await;
+            for (uri, resolves) in [
+                ("/api/explorer/v1/block", true),
+                ("/api/explorer/v1/block/", resolves_with_slash),
+            ] {
+                let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+                assert_eq!(
+                    response.status().is_success(),
+                    resolves,
+                    "{:?} {}",
+                    normalization,
+                    uri
+                );
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn cloned_factory_updates_resolve_to_the_same_endpoints() {
+        let update = UpdateEndpoints::from_future(async {
+            vec![("explorer".to_owned(), ApiBuilder::new())]
+        });
+        let clone = update.clone();
+        assert_eq!(update.resolve().await.len(), 1);
+        assert_eq!(clone.resolve().await.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn run_fails_on_invalid_version_tag() {
+        let config = ApiManagerConfig::default().with_version_tag("1.2.0\nX-Injected: 1");
+        let updates = futures::stream::iter(vec![UpdateEndpoints::new(Vec::new())]);
+        assert!(ApiManager::new(config).run(updates).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn runs_without_servers() {
+        let mut manager = ApiManager::new(ApiManagerConfig::default());
+        let mut events = manager.subscribe();
+        let updates = futures::stream::iter(vec![
+            UpdateEndpoints::new(vec![("explorer".to_owned(), ApiBuilder::new())]),
+            UpdateEndpoints::new(Vec::new()),
+        ]);
+
+        manager.run(updates).await.unwrap();
+        // Both updates are coalesced into a single restart.
+        let event = events.next().await.unwrap();
+        assert!(matches!(
+            event,
+            ManagerEvent::Started { ref addresses, .. } if addresses.is_empty()
+        ));
+        assert!(events.next().await.is_none());
     }
 }
@@ -0,0 +1,198 @@
+use serde::{de::value::Error, ser::Impossible, Serialize, Serializer};
+use time::OffsetDateTime;
+
+use std::cell::RefCell;
+
+use crate::error::{HeaderName, HeaderValue, HttpStatusCode};
+
+/// HTTP properties of a handler response set by the wrappers around its value, such as
+/// `WithStatus` or `WithHeaders`. Wrappers may be nested in any order; for the status and
+/// `Last-Modified`, the outermost one wins.
+#[derive(Debug, Default)]
+pub(crate) struct ResponseMeta {
+    pub(crate) status: Option<HttpStatusCode>,
+    pub(crate) headers: Vec<(HeaderName, HeaderValue)>,
+    pub(crate) last_modified: Option<OffsetDateTime>,
+    pub(crate) no_content: bool,
+    pub(crate) binary: Option<(String, Vec<u8>)>,
+}
+
+thread_local! {
+    static RESPONSE_META: RefCell<Option<ResponseMeta>> = const { RefCell::new(None) };
+}
+
+/// Called by wrappers from their `Serialize` impls; does nothing outside of `collect`.
+pub(crate) fn record(f: impl FnOnce(&mut ResponseMeta)) {
+    RESPONSE_META.with(|meta| {
+        if let Some(ref mut meta) = *meta.borrow_mut() {
+            f(meta);
+        }
+    });
+}
+
+/// Collects the metadata of the wrappers at the top of `value`. The value is "serialized"
+/// with a serializer that fails on the first call made by a non-wrapper type, so wrappers
+/// nested in fields or sequences are never reached.
+pub(crate) fn collect<T: Serialize>(value: &T) -> ResponseMeta {
+    let previous = RESPONSE_META.with(|meta| meta.replace(Some(ResponseMeta::default())));
+    let _ = value.serialize(StopSerializer);
+    RESPONSE_META
+        .with(|meta| meta.replace(previous))
+        .unwrap_or_default()
+}
+
+struct StopSerializer;
+
+fn stop() -> Error {
+    <Error as serde::ser::Error>::custom("not a response wrapper")
+}
+
+impl Serializer for StopSerializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Impossible<(), Error>;
+    type SerializeTuple = Impossible<(), Error>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = Impossible<(), Error>;
+    type SerializeStruct = Impossible<(), Error>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    fn serialize_bool(self, _: bool) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_i16(self, _: i16) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_i32(self, _: i32) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_i64(self, _: i64) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_u8(self, _: u8) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_u16(self, _: u16) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_u32(self, _: u32) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_u64(self, _: u64) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_char(self, _: char) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_str(self, _: &str) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), Error> {
+        Err(stop())
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(stop())
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(stop())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(stop())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(stop())
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(stop())
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        Err(stop())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(stop())
+    }
+}
@@ -0,0 +1,149 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Checks the first successful response of an endpoint against a JSON Schema and logs
+/// a warning on mismatch. Only compiled in debug builds.
+///
+/// Supports the `type`, `enum`, `const`, `properties`, `required`,
+/// `additionalProperties` and `items` keywords; other keywords are ignored.
+#[derive(Debug, Clone)]
+pub(crate) struct SchemaCheck {
+    schema: Arc<Value>,
+    checked: Arc<AtomicBool>,
+}
+
+impl SchemaCheck {
+    pub(crate) fn new(schema: Value) -> Self {
+        Self {
+            schema: Arc::new(schema),
+            checked: Arc::default(),
+        }
+    }
+
+    pub(crate) fn check<T: Serialize>(&self, endpoint: &str, response: &T) {
+        if self.checked.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let value = match serde_json::to_value(response) {
+            Ok(value) => value,
+            Err(e) => {
+                log::warn!(
+                    "Unable to check the response schema of `{}`: {}",
+                    endpoint,
+                    e
+                );
+                return;
+            }
+        };
+        let mut mismatches = Vec::new();
+        validate(&self.schema, &value, "", &mut mismatches);
+        if !mismatches.is_empty() {
+            log::warn!(
+                "Response of endpoint `{}` doesn't match its schema:\n  {}",
+                endpoint,
+                mismatches.join("\n  ")
+            );
+        }
+    }
+}
+
+fn validate(schema: &Value, value: &Value, path: &str, mismatches: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            mismatches.push(format!("{}: no value is allowed", pointer(path)));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(types) = schema.get("type") {
+        let matches = match types {
+            Value::String(ty) => has_type(value, ty),
+            Value::Array(types) => types
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|ty| has_type(value, ty)),
+            _ => true,
+        };
+        if !matches {
+            mismatches.push(format!("{}: expected type {}", pointer(path), types));
+            return;
+        }
+    }
+    if let Some(Value::Array(variants)) = schema.get("enum") {
+        if !variants.contains(value) {
+            mismatches.push(format!(
+                "{}: value is not one of {:?}",
+                pointer(path),
+                variants
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            mismatches.push(format!("{}: expected {}", pointer(path), expected));
+        }
+    }
+
+    match value {
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(name) {
+                        mismatches.push(format!("{}: missing field `{}`", pointer(path), name));
+                    }
+                }
+            }
+            for (name, field) in object {
+                let field_path = format!("{}/{}", path, name);
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(field_schema) => validate(field_schema, field, &field_path, mismatches),
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            validate(additional, field, &field_path, mismatches);
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    let item_path = format!("{}/{}", path, index);
+                    validate(item_schema, item, &item_path, mismatches);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    }
+}
+
+fn pointer(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
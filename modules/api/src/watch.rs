@@ -0,0 +1,55 @@
+use futures::channel::mpsc;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use std::{path::Path, sync::mpsc as std_mpsc, thread, time::Duration};
+
+use crate::UpdateEndpoints;
+
+/// Keeps watching the path while alive.
+#[derive(Debug)]
+pub struct EndpointsWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+/// Watches `path` and, once changes settle for `debounce`, sends the endpoints returned
+/// by `rebuild` into the stream to be passed to `ApiManager::run`.
+pub fn watch_endpoints<P, F>(
+    path: P,
+    debounce: Duration,
+    mut rebuild: F,
+) -> notify::Result<(EndpointsWatcher, mpsc::UnboundedReceiver<UpdateEndpoints>)>
+where
+    P: AsRef<Path>,
+    F: FnMut() -> anyhow::Result<UpdateEndpoints> + Send + 'static,
+{
+    let (events_tx, events_rx) = std_mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if event.kind.is_access() => {}
+            Ok(_) => {
+                events_tx.send(()).ok();
+            }
+            Err(e) => log::warn!("File watcher error: {}", e),
+        })?;
+    watcher.watch(path.as_ref(), RecursiveMode::Recursive)?;
+
+    let (endpoints_tx, endpoints_rx) = mpsc::unbounded();
+    let path = path.as_ref().display().to_string();
+    thread::spawn(move || {
+        while events_rx.recv().is_ok() {
+            while events_rx.recv_timeout(debounce).is_ok() {}
+
+            log::info!("Changes detected in {}, rebuilding endpoints", path);
+            match rebuild() {
+                Ok(update) => {
+                    if endpoints_tx.unbounded_send(update).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("Unable to rebuild endpoints: {:#}", e),
+            }
+        }
+    });
+
+    Ok((EndpointsWatcher { _watcher: watcher }, endpoints_rx))
+}
@@ -1,7 +1,11 @@
-use std::{future::Future, marker::PhantomData};
+use serde::{Serialize, Serializer};
+use std::{fmt, future::Future, marker::PhantomData};
 use time::OffsetDateTime;
 
-use crate::{error, EndpointMutability};
+use crate::{
+    error::{self, HeaderName, HeaderValue, HttpStatusCode},
+    response_meta, ApiKeyAuth, CircuitBreaker, EndpointMutability,
+};
 
 pub type Result<I> = std::result::Result<I, error::Error>;
 
@@ -9,25 +13,331 @@ pub type Result<I> = std::result::Result<I, error::Error>;
 pub struct With<Q, I, R, F> {
     pub handler: F,
     pub actuality: Actuality,
+    pub cache_control: Option<CacheControl>,
+    pub summary: String,
+    pub description: String,
+    pub accept_get_body: bool,
+    pub lenient_content_type: bool,
+    pub required_headers: Vec<String>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub api_key_auth: Option<ApiKeyAuth>,
+    pub enabled: bool,
+    pub output_schema: Option<serde_json::Value>,
     _query_type: PhantomData<Q>,
     _item_type: PhantomData<I>,
     _result_type: PhantomData<R>,
 }
 
+impl<Q, I, R, F> With<Q, I, R, F> {
+    /// Non-standard: lets an immutable (`GET`) endpoint read its query from a JSON body
+    /// when one is sent, falling back to the query string otherwise.
+    pub fn with_get_body(self) -> Self {
+        Self {
+            accept_get_body: true,
+            ..self
+        }
+    }
+
+    /// Accepts request bodies of mutable endpoints regardless of their `Content-Type`,
+    /// which otherwise must be `application/json`.
+    pub fn with_lenient_content_type(self) -> Self {
+        Self {
+            lenient_content_type: true,
+            ..self
+        }
+    }
+
+    /// Disabled endpoints are not routed at all, so requests to them get `404 Not Found`.
+    pub fn with_enabled(self, enabled: bool) -> Self {
+        Self { enabled, ..self }
+    }
+
+    /// In debug builds, checks the first successful response against the given JSON Schema
+    /// and logs a warning on mismatch. Release builds skip the check.
+    pub fn with_schema(self, schema: serde_json::Value) -> Self {
+        Self {
+            output_schema: Some(schema),
+            ..self
+        }
+    }
+
+    pub fn with_circuit_breaker(self, circuit_breaker: CircuitBreaker) -> Self {
+        Self {
+            circuit_breaker: Some(circuit_breaker),
+            ..self
+        }
+    }
+
+    /// Rejects requests without a valid API key with `401 Unauthorized` before any
+    /// per-request work, such as reading the body, is done.
+    pub fn with_api_key_auth(self, api_key_auth: ApiKeyAuth) -> Self {
+        Self {
+            api_key_auth: Some(api_key_auth),
+            ..self
+        }
+    }
+
+    /// Rejects requests lacking the named header with `400 Bad Request`.
+    pub fn with_required_header<S: Into<String>>(mut self, name: S) -> Self {
+        self.required_headers.push(name.into());
+        self
+    }
+
+    pub fn with_summary<S: Into<String>>(self, summary: S) -> Self {
+        Self {
+            summary: summary.into(),
+            ..self
+        }
+    }
+
+    pub fn with_description<S: Into<String>>(self, description: S) -> Self {
+        Self {
+            description: description.into(),
+            ..self
+        }
+    }
+
+    pub fn with_cache_control(self, cache_control: CacheControl) -> Self {
+        Self {
+            cache_control: Some(cache_control),
+            ..self
+        }
+    }
+}
+
+/// Handler response whose headers are appended to the serialized `value`. Like the other
+/// response wrappers, it may wrap or be wrapped by `WithStatus` and `WithLastModified`.
+#[derive(Debug, Clone)]
+pub struct WithHeaders<I> {
+    pub value: I,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl<I> WithHeaders<I> {
+    pub fn new(value: I) -> Self {
+        Self {
+            value,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+impl<I: Serialize> Serialize for WithHeaders<I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        response_meta::record(|meta| meta.headers.extend(self.headers.iter().cloned()));
+        self.value.serialize(serializer)
+    }
+}
+
+/// Handler response sent with the given status instead of `200 OK`.
+#[derive(Debug, Clone)]
+pub struct WithStatus<I> {
+    pub value: I,
+    pub status: HttpStatusCode,
+}
+
+impl<I> WithStatus<I> {
+    pub fn new(value: I, status: HttpStatusCode) -> Self {
+        Self { value, status }
+    }
+}
+
+impl<I: Serialize> Serialize for WithStatus<I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        response_meta::record(|meta| {
+            meta.status.get_or_insert(self.status);
+        });
+        self.value.serialize(serializer)
+    }
+}
+
+/// Handler response of one of two types, serialized as the active variant alone. Wrap
+/// the variants in `WithStatus` to send each with its own status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A: Serialize, B: Serialize> Serialize for Either<A, B> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Either::Left(value) => value.serialize(serializer),
+            Either::Right(value) => value.serialize(serializer),
+        }
+    }
+}
+
+/// Handler response producing `204 No Content` without a body, unlike `()`, which
+/// is still serialized as JSON `null` with `200 OK`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoContent;
+
+impl Serialize for NoContent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        response_meta::record(|meta| meta.no_content = true);
+        serializer.serialize_unit_struct("NoContent")
+    }
+}
+
+/// Handler response sent as-is with the given `Content-Type`, bypassing JSON
+/// serialization; e.g. an encoded protobuf message with `application/x-protobuf`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binary {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Binary {
+    pub fn new<S: Into<String>>(content_type: S, body: Vec<u8>) -> Self {
+        Self {
+            content_type: content_type.into(),
+            body,
+        }
+    }
+}
+
+impl Serialize for Binary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        response_meta::record(|meta| {
+            meta.binary = Some((self.content_type.clone(), self.body.clone()));
+        });
+        serializer.serialize_bytes(&self.body)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WithLastModified<I> {
+    pub value: I,
+    pub last_modified: OffsetDateTime,
+}
+
+impl<I> WithLastModified<I> {
+    pub fn new(value: I, last_modified: OffsetDateTime) -> Self {
+        Self {
+            value,
+            last_modified,
+        }
+    }
+}
+
+impl<I: Serialize> Serialize for WithLastModified<I> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        response_meta::record(|meta| {
+            meta.last_modified.get_or_insert(self.last_modified);
+        });
+        self.value.serialize(serializer)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Actuality {
     Actual,
     Deprecated {
         discontinued_on: Option<OffsetDateTime>,
         description: Option<String>,
+        enforce_sunset: bool,
     },
 }
 
+impl Actuality {
+    pub fn is_sunset(&self, now: OffsetDateTime) -> bool {
+        match *self {
+            Actuality::Deprecated {
+                discontinued_on: Some(date),
+                enforce_sunset: true,
+                ..
+            } => now > date,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CacheVisibility {
+    Public,
+    Private,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub max_age: Option<u64>,
+    pub visibility: Option<CacheVisibility>,
+}
+
+impl CacheControl {
+    pub fn no_store() -> Self {
+        Self {
+            no_store: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn max_age(secs: u64) -> Self {
+        Self {
+            max_age: Some(secs),
+            ..Self::default()
+        }
+    }
+
+    pub fn public(self) -> Self {
+        Self {
+            visibility: Some(CacheVisibility::Public),
+            ..self
+        }
+    }
+
+    pub fn private(self) -> Self {
+        Self {
+            visibility: Some(CacheVisibility::Private),
+            ..self
+        }
+    }
+
+    /// Whether no directive is set, as for `CacheControl::default()`; no `Cache-Control`
+    /// header is sent then.
+    pub fn is_empty(&self) -> bool {
+        !self.no_store && self.max_age.is_none() && self.visibility.is_none()
+    }
+}
+
+impl fmt::Display for CacheControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut directives = Vec::new();
+        match self.visibility {
+            Some(CacheVisibility::Public) => directives.push("public".to_owned()),
+            Some(CacheVisibility::Private) => directives.push("private".to_owned()),
+            None => {}
+        }
+        if self.no_store {
+            directives.push("no-store".to_owned());
+        }
+        if let Some(max_age) = self.max_age {
+            directives.push(format!("max-age={}", max_age));
+        }
+        f.write_str(&directives.join(", "))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Deprecated<Q, I, R, F> {
     pub handler: F,
     pub discontinued_on: Option<OffsetDateTime>,
     pub description: Option<String>,
+    pub cache_control: Option<CacheControl>,
+    pub enforce_sunset: bool,
+    pub summary: String,
+    pub endpoint_description: String,
     _query_type: PhantomData<Q>,
     _item_type: PhantomData<I>,
     _result_type: PhantomData<R>,
@@ -39,12 +349,18 @@ impl<Q, I, R, F> Deprecated<Q, I, R, F> {
             handler,
             discontinued_on: None,
             description: None,
+            cache_control: None,
+            enforce_sunset: false,
+            summary: String::new(),
+            endpoint_description: String::new(),
             _query_type: PhantomData,
             _item_type: PhantomData,
             _result_type: PhantomData,
         }
     }
 
+    /// Sets the discontinuation instant. The date may have any offset: the `Warning` and
+    /// `Sunset` headers always show it converted to GMT, as HTTP dates require.
     pub fn with_date(self, discontinued_on: OffsetDateTime) -> Self {
         Self {
             discontinued_on: Some(discontinued_on),
@@ -59,6 +375,36 @@ impl<Q, I, R, F> Deprecated<Q, I, R, F> {
         }
     }
 
+    pub fn with_cache_control(self, cache_control: CacheControl) -> Self {
+        Self {
+            cache_control: Some(cache_control),
+            ..self
+        }
+    }
+
+    pub fn with_enforced_sunset(self) -> Self {
+        Self {
+            enforce_sunset: true,
+            ..self
+        }
+    }
+
+    pub fn with_summary<S: Into<String>>(self, summary: S) -> Self {
+        Self {
+            summary: summary.into(),
+            ..self
+        }
+    }
+
+    /// Sets the endpoint description reported by `ApiAggregator::routes`. Unlike
+    /// `with_description`, it does not appear in the deprecation warning.
+    pub fn with_endpoint_description<S: Into<String>>(self, description: S) -> Self {
+        Self {
+            endpoint_description: description.into(),
+            ..self
+        }
+    }
+
     pub fn with_different_handler<F1, R1>(self, handler: F1) -> Deprecated<Q, I, R1, F1>
     where
         F1: Fn(Q) -> R1,
@@ -68,6 +414,10 @@ impl<Q, I, R, F> Deprecated<Q, I, R, F> {
             handler,
             discontinued_on: self.discontinued_on,
             description: self.description,
+            cache_control: self.cache_control,
+            enforce_sunset: self.enforce_sunset,
+            summary: self.summary,
+            endpoint_description: self.endpoint_description,
 
             _query_type: PhantomData,
             _item_type: PhantomData,
@@ -93,7 +443,20 @@ impl<Q, I, R, F> From<Deprecated<Q, I, R, F>> for With<Q, I, R, F> {
             actuality: Actuality::Deprecated {
                 discontinued_on: deprecated.discontinued_on,
                 description: deprecated.description,
+                enforce_sunset: deprecated.enforce_sunset,
             },
+            cache_control: deprecated
+                .cache_control
+                .or_else(|| Some(CacheControl::no_store())),
+            summary: deprecated.summary,
+            description: deprecated.endpoint_description,
+            accept_get_body: false,
+            lenient_content_type: false,
+            required_headers: Vec::new(),
+            circuit_breaker: None,
+            api_key_auth: None,
+            enabled: true,
+            output_schema: None,
             _query_type: PhantomData,
             _item_type: PhantomData,
             _result_type: PhantomData,
@@ -144,6 +507,14 @@ impl<Q, I, R, F> NamedWith<Q, I, R, F> {
             mutability: EndpointMutability::Immutable,
         }
     }
+
+    pub fn summary(&self) -> &str {
+        &self.inner.summary
+    }
+
+    pub fn description(&self) -> &str {
+        &self.inner.description
+    }
 }
 
 impl<Q, I, R, F> From<F> for With<Q, I, R, F>
@@ -155,6 +526,16 @@ where
         Self {
             handler,
             actuality: Actuality::Actual,
+            cache_control: None,
+            summary: String::new(),
+            description: String::new(),
+            accept_get_body: false,
+            lenient_content_type: false,
+            required_headers: Vec::new(),
+            circuit_breaker: None,
+            api_key_auth: None,
+            enabled: true,
+            output_schema: None,
             _query_type: PhantomData,
             _item_type: PhantomData,
             _result_type: PhantomData,
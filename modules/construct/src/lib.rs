@@ -2,12 +2,13 @@ use proc_macro2::{Ident, Span, TokenStream};
 use protobuf_codegen::Customize;
 use quote::{quote, ToTokens};
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     env,
-    fs::File,
+    fs::{self, File},
     io::{Read, Write},
     path::{Path, PathBuf},
 };
+#[cfg(feature = "walkdir")]
 use walkdir::WalkDir;
 
 #[derive(Debug, Copy, Clone)]
@@ -16,6 +17,7 @@ pub enum ProtoSources<'a> {
     Crypto,
     Common,
     Merkledb,
+    Dependency(&'a str),
     Path(&'a str),
 }
 
@@ -26,6 +28,7 @@ impl<'a> ProtoSources<'a> {
             ProtoSources::Common => get_nano_protobuf_common_files_path(),
             ProtoSources::Crypto => get_nano_protobuf_crypto_files_path(),
             ProtoSources::Merkledb => get_nano_protobuf_merkledb_files_path(),
+            ProtoSources::Dependency(name) => get_dependency_protobuf_files_path(name),
             ProtoSources::Path(path) => (*path).to_string(),
         }
     }
@@ -43,8 +46,11 @@ struct ProtobufFile {
     relative_path: String,
 }
 
+/// Lists .proto files under `path` sorted by relative path, so that the generated code
+/// does not depend on the traversal order of the file system.
+#[cfg(feature = "walkdir")]
 fn get_proto_files<P: AsRef<Path>>(path: &P) -> Vec<ProtobufFile> {
-    WalkDir::new(path)
+    let mut files: Vec<_> = WalkDir::new(path)
         .into_iter()
         .filter_map(|e| {
             let entry = e.ok()?;
@@ -63,6 +69,46 @@ fn get_proto_files<P: AsRef<Path>>(path: &P) -> Vec<ProtobufFile> {
                 None
             }
         })
+        .collect();
+    files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+    files
+}
+
+#[cfg(not(feature = "walkdir"))]
+fn get_proto_files<P: AsRef<Path>>(path: &P) -> Vec<ProtobufFile> {
+    panic!(
+        "Cannot discover .proto files in `{}` without the `walkdir` feature, \
+         use `ProtobufGenerator::with_explicit_inputs` instead",
+        path.as_ref().display()
+    )
+}
+
+fn get_explicit_proto_files(input_dir: &str, inputs: &[&str]) -> Vec<ProtobufFile> {
+    inputs
+        .iter()
+        .map(|input| ProtobufFile {
+            full_path: Path::new(input_dir).join(input),
+            relative_path: canonicalize_protobuf_path(input),
+        })
+        .collect()
+}
+
+/// Include files named by path, relative to the first include dir that contains them.
+fn get_explicit_included_files(includes: &[&str], files: &[&str]) -> Vec<ProtobufFile> {
+    files
+        .iter()
+        .map(|file| {
+            let relative_path = includes
+                .iter()
+                .find_map(|include| Path::new(file).strip_prefix(include).ok())
+                .unwrap_or_else(|| panic!("`{}` is not in any include directory", file))
+                .to_str()
+                .expect("Cannot convert relative path to string");
+            ProtobufFile {
+                full_path: PathBuf::from(file),
+                relative_path: canonicalize_protobuf_path(relative_path),
+            }
+        })
         .collect()
 }
 
@@ -78,6 +124,8 @@ fn canonicalize_protobuf_path(path_str: &str) -> String {
 
 fn include_proto_files(proto_files: HashSet<&ProtobufFile>, name: &str) -> impl ToTokens {
     let proto_files_len = proto_files.len();
+    let mut proto_files: Vec<_> = proto_files.into_iter().collect();
+    proto_files.sort_by_key(|file| (&file.relative_path, &file.full_path));
 
     let proto_files = proto_files.iter().map(|file| {
         let name = &file.relative_path;
@@ -103,77 +151,229 @@ fn include_proto_files(proto_files: HashSet<&ProtobufFile>, name: &str) -> impl
     }
 }
 
-fn get_mod_files(proto_files: &[ProtobufFile]) -> impl Iterator<Item = TokenStream> + '_ {
-    proto_files.iter().map(|file| {
-        let mod_name = file
-            .full_path
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .expect(".proto file name is not convertible to &str");
+/// FNV-1a over the relative paths and contents of `proto_files`, in path order. It is a
+/// schema revision fingerprint rather than a cryptographic digest.
+fn proto_sources_hash(proto_files: &[ProtobufFile]) -> impl ToTokens {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut files: Vec<_> = proto_files.iter().collect();
+    files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+    let mut hash = OFFSET_BASIS;
+    for file in files {
+        let content = fs::read(&file.full_path).expect("Unable to read .proto file");
+        let parts = [file.relative_path.as_bytes(), &[0], &content, &[0]];
+        for byte in parts.iter().flat_map(|part| part.iter()) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
 
-        let mod_name = Ident::new(mod_name, Span::call_site());
-        if mod_name == "tests" {
-            quote! {
-                #[cfg(test)] pub mod #mod_name;
+    let hash = format!("{:016x}", hash);
+    quote! {
+        #[allow(dead_code)]
+        pub const PROTO_SOURCES_HASH: &str = #hash;
+    }
+}
+
+fn file_stem(file: &ProtobufFile) -> &str {
+    file.full_path
+        .file_stem()
+        .unwrap()
+        .to_str()
+        .expect(".proto file name is not convertible to &str")
+}
+
+fn file_dir(file: &ProtobufFile) -> &str {
+    file.relative_path
+        .rsplit_once('/')
+        .map_or("", |(dir, _)| dir)
+}
+
+/// Panics with a readable message instead of letting `Ident::new` fail on names such as
+/// `v1.0` or `my-types`, which cannot be used as module names.
+fn module_ident(name: &str, path: &str) -> Ident {
+    let mut chars = name.chars();
+    let is_valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name != "_";
+    assert!(
+        is_valid,
+        "`{}` in `{}` is not a valid Rust identifier and cannot be used as a module name",
+        name, path
+    );
+    Ident::new(name, Span::call_site())
+}
+
+fn mod_declaration(file: &ProtobufFile) -> TokenStream {
+    let mod_name = module_ident(file_stem(file), &file.relative_path);
+    if mod_name == "tests" {
+        quote! {
+            #[cfg(test)] pub mod #mod_name;
+        }
+    } else {
+        quote! {
+            pub mod #mod_name;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ModTree<'a> {
+    files: Vec<&'a ProtobufFile>,
+    children: BTreeMap<&'a str, ModTree<'a>>,
+}
+
+impl<'a> ModTree<'a> {
+    fn new(proto_files: &'a [ProtobufFile]) -> Self {
+        let mut stems = HashMap::new();
+        for file in proto_files {
+            if let Some(other) = stems.insert(file_stem(file), &file.relative_path) {
+                panic!(
+                    "`{}` and `{}` would both be generated as `{}.rs`, \
+                     nested modules require unique .proto file names",
+                    other,
+                    file.relative_path,
+                    file_stem(file)
+                );
             }
-        } else {
+        }
+
+        let mut root = Self::default();
+        for file in proto_files {
+            let node = file_dir(file)
+                .split('/')
+                .filter(|component| !component.is_empty())
+                .fold(&mut root, |node, component| {
+                    node.children.entry(component).or_default()
+                });
+            node.files.push(file);
+        }
+        root
+    }
+
+    /// Declarations for this node at `path`. Generated code refers to imported files as
+    /// `super::{stem}`, so every module also imports the files of other directories by
+    /// their stem.
+    fn items(&self, proto_files: &[ProtobufFile], path: &[&str]) -> Vec<TokenStream> {
+        let children = self.children.iter().map(|(name, child)| {
+            let mut child_path = path.to_vec();
+            child_path.push(name);
+            let items = child.items(proto_files, &child_path);
+            let name = module_ident(name, &child_path.join("/"));
             quote! {
-                pub mod #mod_name;
+                pub mod #name {
+                    #( #items )*
+                }
             }
-        }
-    })
+        });
+
+        let prefix: Vec<_> = if path.is_empty() {
+            vec![Ident::new("self", Span::call_site())]
+        } else {
+            path.iter()
+                .map(|_| Ident::new("super", Span::call_site()))
+                .collect()
+        };
+        let dir = path.join("/");
+        let imports = proto_files
+            .iter()
+            .filter(|file| file_dir(file) != dir && !self.children.contains_key(file_stem(file)))
+            .map(|file| {
+                let components = file_dir(file)
+                    .split('/')
+                    .filter(|component| !component.is_empty())
+                    .map(|component| Ident::new(component, Span::call_site()));
+                let stem = module_ident(file_stem(file), &file.relative_path);
+                let cfg = if stem == "tests" {
+                    quote!(#[cfg(test)])
+                } else {
+                    quote!()
+                };
+                quote! {
+                    #cfg #[allow(unused_imports)] use #( #prefix:: )* #( #components:: )* #stem;
+                }
+            });
+
+        children
+            .chain(self.files.iter().map(|file| mod_declaration(file)))
+            .chain(imports)
+            .collect()
+    }
+}
+
+/// Module declarations for `proto_files`; with `nested`, source subdirectories become
+/// nested modules, so `a/b/types.proto` is declared as `a::b::types`.
+fn get_mod_files(proto_files: &[ProtobufFile], nested: bool) -> Vec<TokenStream> {
+    if nested {
+        ModTree::new(proto_files).items(proto_files, &[])
+    } else {
+        proto_files.iter().map(mod_declaration).collect()
+    }
 }
 
-fn generate_mod_rs(
-    out_dir: impl AsRef<Path>,
+fn mod_rs_content(
+    mod_files: Vec<TokenStream>,
     proto_files: &[ProtobufFile],
     includes: &[ProtobufFile],
-    mod_file: impl AsRef<Path>,
-) {
-    let mod_files = get_mod_files(proto_files);
-
+) -> TokenStream {
     let includes = includes
         .iter()
         .filter(|file| !proto_files.contains(file))
         .collect();
 
+    let hash = proto_sources_hash(proto_files);
     let proto_files = include_proto_files(proto_files.iter().collect(), "PROTO_SOURCES");
     let includes = include_proto_files(includes, "INCLUDES");
 
-    let content = quote! {
+    quote! {
         #( #mod_files )*
         #proto_files
         #includes
-    };
-
-    let dest_path = out_dir.as_ref().join(mod_file);
-    let mut file = File::create(dest_path).expect("Unable to create output file");
-    file.write_all(content.into_token_stream().to_string().as_bytes())
-        .expect("Unable to write data to file");
+        #hash
+    }
 }
 
-fn generate_mod_rs_without_sources(
-    out_dir: impl AsRef<Path>,
+fn mod_rs_content_without_sources(
+    mod_files: Vec<TokenStream>,
     proto_files: &[ProtobufFile],
-    mod_file: impl AsRef<Path>,
-) {
-    let mod_files = get_mod_files(proto_files);
-    let content = quote! {
+) -> TokenStream {
+    let hash = proto_sources_hash(proto_files);
+    quote! {
         #( #mod_files )*
-    };
+        #hash
+    }
+}
+
+fn write_mod_rs(out_dir: impl AsRef<Path>, mod_file: impl AsRef<Path>, content: TokenStream) {
     let dest_path = out_dir.as_ref().join(mod_file);
     let mut file = File::create(dest_path).expect("Unable to create output file");
     file.write_all(content.into_token_stream().to_string().as_bytes())
         .expect("Unable to write data to file");
 }
 
+#[derive(Debug, Clone)]
+pub struct GenerationPlan {
+    pub proto_files: Vec<PathBuf>,
+    pub includes: Vec<String>,
+    pub modules: Vec<String>,
+    pub mod_rs: String,
+}
+
 #[derive(Debug)]
 pub struct ProtobufGenerator<'a> {
     includes: Vec<ProtoSources<'a>>,
     mod_name: &'a str,
     input_dir: &'a str,
     include_sources: bool,
+    strict_includes: bool,
+    nested_modules: bool,
+    explicit_inputs: Option<&'a [&'a str]>,
+    explicit_includes: Option<&'a [&'a str]>,
+    output_dir: &'a str,
 }
 
 impl<'a> ProtobufGenerator<'a> {
@@ -184,6 +384,11 @@ impl<'a> ProtobufGenerator<'a> {
             input_dir: "",
             mod_name,
             include_sources: true,
+            strict_includes: false,
+            nested_modules: false,
+            explicit_inputs: None,
+            explicit_includes: None,
+            output_dir: "",
         }
     }
     pub fn with_input_dir(mut self, path: &'a str) -> Self {
@@ -221,6 +426,11 @@ impl<'a> ProtobufGenerator<'a> {
         self
     }
 
+    pub fn with_dependency(mut self, name: &'a str) -> Self {
+        self.includes.push(ProtoSources::Dependency(name));
+        self
+    }
+
     pub fn with_includes(mut self, includes: &'a [ProtoSources<'_>]) -> Self {
         self.includes.extend_from_slice(includes);
         self
@@ -231,52 +441,225 @@ impl<'a> ProtobufGenerator<'a> {
         self
     }
 
+    pub fn with_strict_includes(mut self) -> Self {
+        self.strict_includes = true;
+        self
+    }
+
+    /// Mirrors the directory structure of the input dir as nested modules instead of
+    /// declaring every file at the top level. Codegen runs over all files at once, so
+    /// imports across directories resolve; since generated files refer to each other by
+    /// file name, .proto file names must be unique across directories.
+    pub fn with_nested_modules(mut self) -> Self {
+        self.nested_modules = true;
+        self
+    }
+
+    /// Uses the given .proto files, relative to the input dir, instead of discovering them
+    /// by walking the input dir. Include dirs are not scanned either, so `INCLUDES`
+    /// in the generated `mod.rs` only lists the files passed to `with_explicit_includes`
+    /// and empty include dirs are not reported.
+    pub fn with_explicit_inputs(mut self, inputs: &'a [&'a str]) -> Self {
+        self.explicit_inputs = Some(inputs);
+        self
+    }
+
+    /// Lists the .proto files of the include dirs for `INCLUDES` instead of walking the
+    /// include dirs. Paths must start with the include dir containing the file; passing
+    /// every file of the include dirs yields the same `mod.rs` as discovery.
+    pub fn with_explicit_includes(mut self, files: &'a [&'a str]) -> Self {
+        self.explicit_includes = Some(files);
+        self
+    }
+
+    pub fn with_output_dir(mut self, path: &'a str) -> Self {
+        self.output_dir = path;
+        self
+    }
+
+    pub fn dry_run(&self) -> GenerationPlan {
+        assert!(!self.input_dir.is_empty(), "Input dir is not specified");
+        assert!(!self.includes.is_empty(), "Includes are not specified");
+
+        let includes = self.resolve_includes();
+        let includes: Vec<&str> = includes.iter().map(String::as_str).collect();
+        let proto_files = self.proto_files();
+        let mod_rs = self.mod_rs_content(&proto_files, &includes);
+
+        GenerationPlan {
+            modules: get_mod_files(&proto_files, self.nested_modules)
+                .into_iter()
+                .map(|tokens| tokens.to_string())
+                .collect(),
+            proto_files: proto_files.into_iter().map(|f| f.full_path).collect(),
+            includes: includes.into_iter().map(str::to_owned).collect(),
+            mod_rs: mod_rs.to_string(),
+        }
+    }
+
     pub fn generate(self) {
         assert!(!self.input_dir.is_empty(), "Input dir is not specified");
         assert!(!self.includes.is_empty(), "Includes are not specified");
-        protobuf_generate(
-            self.input_dir,
-            &self.includes,
-            self.mod_name,
-            self.include_sources,
-        );
+        protobuf_generate(&self);
+    }
+
+    fn resolve_includes(&self) -> Vec<String> {
+        let mut includes: Vec<_> = self.includes.iter().map(ProtoSources::path).collect();
+        includes.push(self.input_dir.to_owned());
+        includes
+    }
+
+    fn proto_files(&self) -> Vec<ProtobufFile> {
+        match self.explicit_inputs {
+            Some(inputs) => get_explicit_proto_files(self.input_dir, inputs),
+            None => get_proto_files(&self.input_dir),
+        }
+    }
+
+    fn mod_rs_content(&self, proto_files: &[ProtobufFile], includes: &[&str]) -> TokenStream {
+        let mod_files = get_mod_files(proto_files, self.nested_modules);
+        if self.include_sources {
+            let included_files = match (self.explicit_includes, self.explicit_inputs) {
+                (Some(files), _) => get_explicit_included_files(includes, files),
+                (None, Some(_)) => Vec::new(),
+                (None, None) => get_included_files(includes),
+            };
+            mod_rs_content(mod_files, proto_files, &included_files)
+        } else {
+            mod_rs_content_without_sources(mod_files, proto_files)
+        }
     }
 }
 
-fn protobuf_generate(
-    input_dir: &str,
-    includes: &[ProtoSources<'_>],
-    mod_file_name: &str,
-    include_sources: bool,
-) {
+fn protobuf_generate(generator: &ProtobufGenerator<'_>) {
+    let mod_file_name = generator.mod_name;
+
     let out_dir = env::var("OUT_DIR")
         .map(PathBuf::from)
-        .expect("Unable to get OUT_DIR");
+        .expect("Unable to get OUT_DIR")
+        .join(generator.output_dir);
+    fs::create_dir_all(&out_dir).expect("Unable to create output directory");
 
-    let includes: Vec<_> = includes.iter().map(ProtoSources::path).collect();
-    let mut includes: Vec<&str> = includes.iter().map(String::as_str).collect();
-    includes.push(input_dir);
+    let includes = generator.resolve_includes();
+    let includes: Vec<&str> = includes.iter().map(String::as_str).collect();
 
-    let proto_files = get_proto_files(&input_dir);
+    if generator.explicit_inputs.is_none() {
+        check_empty_includes(&includes, generator.strict_includes);
+    }
 
-    if include_sources {
-        let included_files = get_included_files(&includes);
-        generate_mod_rs(&out_dir, &proto_files, &included_files, mod_file_name);
-    } else {
-        generate_mod_rs_without_sources(&out_dir, &proto_files, mod_file_name);
+    let proto_files = generator.proto_files();
+    check_unused_includes(&proto_files, &includes, generator.input_dir);
+    let content = generator.mod_rs_content(&proto_files, &includes);
+    write_mod_rs(&out_dir, mod_file_name, content);
+
+    let inputs: Vec<_> = proto_files.iter().map(|f| f.full_path.clone()).collect();
+    run_codegen(out_dir.clone(), &inputs, &includes);
+
+    if generator.nested_modules {
+        for file in &proto_files {
+            let dir = file_dir(file);
+            if dir.is_empty() {
+                continue;
+            }
+            let file_name = format!("{}.rs", file_stem(file));
+            fs::create_dir_all(out_dir.join(dir)).expect("Unable to create output directory");
+            fs::rename(out_dir.join(&file_name), out_dir.join(dir).join(&file_name))
+                .expect("Unable to move generated file into its module directory");
+        }
     }
+}
 
-    protobuf_codegen::Codegen::new()
+fn run_codegen(out_dir: PathBuf, inputs: &[PathBuf], includes: &[&str]) {
+    let result = protobuf_codegen::Codegen::new()
         .pure()
         .out_dir(out_dir)
-        .inputs(proto_files.into_iter().map(|f| f.full_path))
-        .includes(&includes)
+        .inputs(inputs)
+        .includes(includes)
         .customize(
             Customize::default()
                 .generate_accessors(true)
                 .gen_mod_rs(true),
         )
-        .run_from_script()
+        .run();
+
+    if let Err(e) = result {
+        let inputs: Vec<_> = inputs
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        panic!(
+            "Protobuf codegen failed (pure mode): {:#}\n  inputs: [{}]\n  includes: [{}]",
+            e,
+            inputs.join(", "),
+            includes.join(", ")
+        );
+    }
+}
+
+fn check_empty_includes(includes: &[&str], strict: bool) {
+    let mut checked = HashSet::new();
+    for include in includes {
+        if checked.insert(include) && get_proto_files(include).is_empty() {
+            let message = format!("Include directory `{}` contains no .proto files", include);
+            if strict {
+                panic!("{}", message);
+            }
+            println!("cargo:warning={}", message);
+        }
+    }
+}
+
+/// Warns about include directories that resolve none of the imports of the input files,
+/// directly or through other imports. Imports resolve to the first include containing them.
+fn check_unused_includes(proto_files: &[ProtobufFile], includes: &[&str], input_dir: &str) {
+    let mut used = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut pending: Vec<_> = proto_files.iter().map(|f| f.full_path.clone()).collect();
+    while let Some(path) = pending.pop() {
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        for import in proto_imports(&content) {
+            let resolved = includes
+                .iter()
+                .find(|include| Path::new(include).join(import).is_file());
+            if let Some(include) = resolved {
+                used.insert(*include);
+                pending.push(Path::new(include).join(import));
+            }
+        }
+    }
+
+    let mut reported = HashSet::new();
+    for include in includes {
+        if *include != input_dir && !used.contains(include) && reported.insert(include) {
+            println!(
+                "cargo:warning=Include directory `{}` resolves no imports and can be removed",
+                include
+            );
+        }
+    }
+}
+
+/// Paths of the files imported by the .proto source `content`.
+fn proto_imports(content: &str) -> Vec<&str> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim_start().strip_prefix("import")?.trim_start();
+            let rest = rest
+                .strip_prefix("public")
+                .or_else(|| rest.strip_prefix("weak"))
+                .unwrap_or(rest)
+                .trim_start();
+            let (path, _) = rest.strip_prefix('"')?.split_once('"')?;
+            Some(path)
+        })
+        .collect()
 }
 
 fn get_included_files(includes: &[&str]) -> Vec<ProtobufFile> {
@@ -300,4 +683,102 @@ fn get_nano_protobuf_common_files_path() -> String {
 fn get_nano_protobuf_merkledb_files_path() -> String {
     env::var("DEP_NANO_PROTOBUF_MERKLEDB_PROTOS")
         .expect("Failed to get nano merkledb protobuf path")
-}
\ No newline at end of file
+}
+
+fn get_dependency_protobuf_files_path(name: &str) -> String {
+    let var = format!("DEP_{}_PROTOS", name.to_uppercase().replace('-', "_"));
+    env::var(&var).unwrap_or_else(|_| panic!("Failed to get {} protobuf path from {}", name, var))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proto_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = env::temp_dir().join(format!("construct-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn nested_modules_import_other_directories() {
+        let dir = proto_dir(
+            "nested",
+            &[
+                ("a/b/types.proto", "syntax = \"proto3\";"),
+                ("c/service.proto", "import \"a/b/types.proto\";"),
+                ("root.proto", "syntax = \"proto3\";"),
+            ],
+        );
+        let plan = ProtobufGenerator::with_mod_name("mod.rs")
+            .with_input_dir(&dir)
+            .with_nested_modules()
+            .dry_run();
+
+        let expected_c = quote! {
+            pub mod c {
+                pub mod service;
+                #[allow(unused_imports)] use super::a::b::types;
+                #[allow(unused_imports)] use super::root;
+            }
+        };
+        assert!(plan.mod_rs.contains(&expected_c.to_string()));
+        let expected_root = quote! {
+            pub mod root;
+            #[allow(unused_imports)] use self::a::b::types;
+            #[allow(unused_imports)] use self::c::service;
+        };
+        assert!(plan.mod_rs.contains(&expected_root.to_string()));
+    }
+
+    #[test]
+    fn explicit_files_generate_same_mod_rs() {
+        let input_dir = proto_dir(
+            "explicit-inputs",
+            &[("service.proto", "import \"types.proto\";")],
+        );
+        let include_dir = proto_dir("explicit-includes", &[("types.proto", "")]);
+
+        let discovered = ProtobufGenerator::with_mod_name("mod.rs")
+            .with_input_dir(&input_dir)
+            .add_path(&include_dir)
+            .dry_run();
+
+        let include_file = format!("{}/types.proto", include_dir);
+        let includes = [include_file.as_str()];
+        let explicit = ProtobufGenerator::with_mod_name("mod.rs")
+            .with_input_dir(&input_dir)
+            .add_path(&include_dir)
+            .with_explicit_inputs(&["service.proto"])
+            .with_explicit_includes(&includes)
+            .dry_run();
+
+        assert!(discovered.mod_rs.contains("\"types.proto\""));
+        assert_eq!(explicit.mod_rs, discovered.mod_rs);
+    }
+
+    #[test]
+    #[should_panic(expected = "`my-types` in `my-types` is not a valid Rust identifier")]
+    fn invalid_directory_names_are_reported() {
+        let dir = proto_dir("invalid", &[("my-types/types.proto", "")]);
+        ProtobufGenerator::with_mod_name("mod.rs")
+            .with_input_dir(&dir)
+            .with_nested_modules()
+            .dry_run();
+    }
+
+    #[test]
+    #[should_panic(expected = "would both be generated as `types.rs`")]
+    fn nested_modules_require_unique_file_names() {
+        let dir = proto_dir("duplicate", &[("a/types.proto", ""), ("b/types.proto", "")]);
+        ProtobufGenerator::with_mod_name("mod.rs")
+            .with_input_dir(&dir)
+            .with_nested_modules()
+            .dry_run();
+    }
+}